@@ -0,0 +1,66 @@
+//! Raw records of every LLM call for auditing: with `AUDIT_DIR` set, the
+//! full request and response of each call are written to a timestamped
+//! JSON file in that directory, with secrets scrubbed.
+
+use crate::{progress, Config};
+use regex::Regex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Numbers the records of a run, so that calls within the same
+/// millisecond get files of their own and sort in call order.
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Replaces API keys in a record by a placeholder: the configured keys
+/// wherever they show up, and anything that looks like a key.
+fn scrub(text: &str) -> String {
+    let mut text = text.to_string();
+    for name in ["OPENAI_API_KEY", "ANTHROPIC_API_KEY"] {
+        if let Ok(key) = std::env::var(name) {
+            if !key.trim().is_empty() {
+                text = text.replace(key.trim(), "[REDACTED]");
+            }
+        }
+    }
+    Regex::new(r"sk-[A-Za-z0-9_-]{16,}")
+        .unwrap()
+        .replace_all(&text, "[REDACTED]")
+        .to_string()
+}
+
+/// Writes the request and response of an LLM call to the audit
+/// directory. Failures are reported, but do not stop the run.
+pub fn record(config: &Config, call: &str, request: &impl Serialize, response: &impl Serialize) {
+    let Some(dir) = &config.audit_dir else {
+        return;
+    };
+    let now = chrono::Local::now();
+    let entry = serde_json::json!({
+        "time": now.to_rfc3339(),
+        "provider_call": call,
+        "model": config.model,
+        "request": request,
+        "response": response,
+    });
+    let path = std::path::Path::new(dir).join(format!(
+        "{}-{:04}-{}.json",
+        now.format("%Y%m%dT%H%M%S%.3f"),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        call
+    ));
+    let written = serde_json::to_string_pretty(&entry)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::write(&path, scrub(&json)))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        progress!(
+            config,
+            "Could not write audit record {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
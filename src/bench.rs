@@ -0,0 +1,153 @@
+//! The `bench` subcommand: runs the whole pipeline for one question with
+//! several models, one after the other, and compares how long the stages
+//! took and what they cost.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wikirag::events::{EventSink, PipelineEvent};
+use wikirag::{
+    answer_question, get_config_from_env, provider_for_model, report_error, retrieve, Config,
+};
+
+struct Measurement {
+    model: String,
+    keywords: Duration,
+    search: Duration,
+    download: Duration,
+    answer: Duration,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+}
+
+/// Parses `--question Q --models m1,m2,...`, both also as `--opt=value`.
+fn parse_args(args: &[String]) -> Result<(String, Vec<String>), String> {
+    let mut question = None;
+    let mut models = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let value = match value.or_else(|| it.next().cloned()) {
+            Some(v) => v,
+            None => return Err(format!("Option {} needs a value", name)),
+        };
+        match name {
+            "--question" => question = Some(value),
+            "--models" => {
+                models = Some(
+                    value
+                        .split(',')
+                        .map(|m| m.trim().to_string())
+                        .filter(|m| !m.is_empty())
+                        .collect::<Vec<_>>(),
+                )
+            }
+            _ => return Err(format!("Unknown bench option {}", name)),
+        }
+    }
+    let question = question
+        .filter(|q| !q.trim().is_empty())
+        .ok_or("Option --question is required")?;
+    let models = models
+        .filter(|m| !m.is_empty())
+        .ok_or("Option --models is required")?;
+    for m in models.iter() {
+        if provider_for_model(m).is_none() {
+            return Err(format!("Unknown model {}", m));
+        }
+    }
+    Ok((question, models))
+}
+
+/// Runs the pipeline once with the given model. The keyword and search
+/// stages end with their events, downloading ends when `retrieve`
+/// returns.
+async fn measure(model: &str, question: &str) -> Measurement {
+    let mut config: Config = get_config_from_env();
+    config.model = model.to_string();
+    config.llm_server = provider_for_model(model).unwrap();
+    config.interactive = false;
+
+    let marks: Arc<Mutex<Vec<Instant>>> = Arc::new(Mutex::new(vec![]));
+    let recorder = marks.clone();
+    config.events = EventSink::handler(move |_, event| {
+        if let PipelineEvent::KeywordsDerived { .. } | PipelineEvent::SearchCompleted { .. } = event
+        {
+            recorder.lock().unwrap().push(Instant::now());
+        }
+    });
+
+    let start = Instant::now();
+    let retrieval = retrieve(&config, question)
+        .await
+        .unwrap_or_else(|e| std::process::exit(report_error(&e)));
+    let retrieved = Instant::now();
+    if let Err(e) = answer_question(&config, &retrieval, &[], question).await {
+        std::process::exit(report_error(&e));
+    }
+    let answered = Instant::now();
+
+    let marks = marks.lock().unwrap().clone();
+    let keywords_done = marks.first().copied().unwrap_or(start);
+    let search_done = marks.last().copied().unwrap_or(keywords_done);
+    Measurement {
+        model: model.to_string(),
+        keywords: keywords_done - start,
+        search: search_done - keywords_done,
+        download: retrieved - search_done,
+        answer: answered - retrieved,
+        prompt_tokens: config.usage.prompt_tokens(),
+        completion_tokens: config.usage.completion_tokens(),
+        cost: config.usage.cost(),
+    }
+}
+
+pub async fn run(args: &[String]) {
+    let (question, models) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!(
+                "Error: {}\nUsage: wikirag bench --question \"...\" --models m1,m2,...",
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let mut results = vec![];
+    for model in models.iter() {
+        eprintln!("\nBenchmarking model {}...", model);
+        results.push(measure(model, &question).await);
+    }
+
+    println!(
+        "{:<16}| {:>9} | {:>9} | {:>9} | {:>9} | {:>9} | {:>9} | {:>10} | {:>10}",
+        "model",
+        "keywords",
+        "search",
+        "download",
+        "answer",
+        "total",
+        "tokens in",
+        "tokens out",
+        "cost"
+    );
+    println!("{}", "=".repeat(112));
+    for m in results.iter() {
+        println!(
+            "{:<16}| {:>8.2}s | {:>8.2}s | {:>8.2}s | {:>8.2}s | {:>8.2}s | {:>9} | {:>10} | ${:>9.6}",
+            m.model,
+            m.keywords.as_secs_f64(),
+            m.search.as_secs_f64(),
+            m.download.as_secs_f64(),
+            m.answer.as_secs_f64(),
+            (m.keywords + m.search + m.download + m.answer).as_secs_f64(),
+            m.prompt_tokens,
+            m.completion_tokens,
+            m.cost
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! A file cache of downloaded page extracts, so that repeated questions
+//! about a topic do not download the same pages again. Entries are files
+//! named `{lang}-{page_id}.txt`, or `{lang}-{page_id}-intro.txt` for
+//! lead sections, and are stale after `CACHE_TTL_DAYS`.
+
+use crate::Config;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// `$XDG_CACHE_HOME/wikirag`, or `~/.cache/wikirag`.
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    match std::env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("wikirag")),
+        _ => {
+            let home = std::env::var("HOME").ok()?;
+            Some(PathBuf::from(home).join(".cache/wikirag"))
+        }
+    }
+}
+
+fn entry(config: &Config, page_id: &str) -> Option<PathBuf> {
+    if !config.page_cache {
+        return None;
+    }
+    // Lead sections and whole articles are kept apart:
+    let suffix = if config.intro_only { "-intro" } else { "" };
+    Some(cache_dir()?.join(format!("{}-{}{}.txt", config.language, page_id, suffix)))
+}
+
+/// The entry of a page, if it exists and is not stale.
+fn fresh_entry(config: &Config, page_id: &str) -> Option<PathBuf> {
+    let path = entry(config, page_id)?;
+    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    if age > Duration::from_secs(config.cache_ttl_days * 24 * 60 * 60) {
+        return None;
+    }
+    Some(path)
+}
+
+/// Whether the extract of a page is cached and not stale.
+pub fn contains(config: &Config, page_id: &str) -> bool {
+    fresh_entry(config, page_id).is_some()
+}
+
+/// The cached extract of a page, unless there is none or it is stale.
+pub fn get(config: &Config, page_id: &str) -> Option<String> {
+    let path = fresh_entry(config, page_id)?;
+    let text = std::fs::read_to_string(&path).ok()?;
+    if config.verbose {
+        eprintln!("Using cached extract {}", path.display());
+    }
+    Some(text)
+}
+
+/// Stores the extract of a page. A cache which cannot be written only
+/// costs a download next time, so failures are just reported.
+pub fn put(config: &Config, page_id: &str, text: &str) {
+    let Some(path) = entry(config, page_id) else {
+        return;
+    };
+    let written = match path.parent() {
+        Some(dir) => std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, text)),
+        None => std::fs::write(&path, text),
+    };
+    if let Err(e) = written {
+        if config.verbose {
+            eprintln!("Could not cache the extract in {}: {}", path.display(), e);
+        }
+    }
+}
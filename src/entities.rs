@@ -0,0 +1,56 @@
+//! Named entities in a question without an LLM: quoted phrases and runs
+//! of capitalized words, like "Marie Curie" or "New York". Capitalized
+//! stopwords, as at the start of a question, are not entities.
+
+use crate::stopwords;
+
+/// Characters which end a run of capitalized words.
+fn ends_run(word: &str) -> bool {
+    word.ends_with([',', '.', '?', '!', ';', ':', '"', ')'])
+}
+
+/// The entities of the question in their order, without duplicates.
+pub fn entities(question: &str, language: &str) -> Vec<String> {
+    let stop = stopwords::builtin(language);
+    let mut found: Vec<String> = vec![];
+    let mut add = |entity: String| {
+        if !entity.is_empty() && !found.iter().any(|f| f.eq_ignore_ascii_case(&entity)) {
+            found.push(entity);
+        }
+    };
+
+    // Quoted phrases are entities as they are, the rest is searched for
+    // capitalized words:
+    let mut rest: Vec<&str> = vec![];
+    for (i, part) in question.split(['"', '“', '”']).enumerate() {
+        if i % 2 == 1 {
+            add(part.trim().to_string());
+        } else {
+            rest.push(part);
+        }
+    }
+
+    let rest = rest.join(" , ");
+    let mut run: Vec<&str> = vec![];
+    for word in rest.split_whitespace() {
+        let w = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '-' && c != '\'');
+        let w = w
+            .strip_suffix("'s")
+            .or_else(|| w.strip_suffix('\''))
+            .unwrap_or(w);
+        let capital =
+            w.chars().next().is_some_and(|c| c.is_uppercase()) && !stop.contains(&w.to_lowercase());
+        if capital {
+            run.push(w);
+        } else {
+            add(run.join(" "));
+            run.clear();
+        }
+        if ends_run(word) {
+            add(run.join(" "));
+            run.clear();
+        }
+    }
+    add(run.join(" "));
+    found
+}
@@ -0,0 +1,106 @@
+//! The errors of the pipeline, by the stage which failed, so that a
+//! Wikipedia outage can be told apart from a problem with the LLM. Every
+//! error has its own exit code, see [`WikiRagError::exit_code`].
+
+use crate::{is_connect_error, is_model_unavailable};
+use thiserror::Error;
+
+pub type BoxError = Box<dyn std::error::Error>;
+
+#[derive(Debug, Error)]
+pub enum WikiRagError {
+    #[error("Wikipedia search failed: {0}")]
+    Search(#[source] BoxError),
+    #[error("Wikipedia download failed: {0}")]
+    Download(#[source] BoxError),
+    #[error("LLM request failed: {0}")]
+    Llm(#[source] BoxError),
+    /// A response which could not be read, whichever stage it was in.
+    #[error("could not parse the response: {0}")]
+    Parse(#[source] BoxError),
+    #[error("{0}")]
+    Config(String),
+    /// The API key of the configured provider is not set.
+    #[error("{0}")]
+    MissingApiKey(String),
+    /// The configured provider cannot be reached or lacks the model.
+    #[error("{0}")]
+    Provider(String),
+    /// The network, or the local Ollama server, cannot be reached at all.
+    #[error("{0}")]
+    Offline(#[source] BoxError),
+    /// The provider does not know the model, or has retired it.
+    #[error("{0}")]
+    ModelUnavailable(#[source] BoxError),
+    /// The answer could not be written to the output file or serialized.
+    #[error("could not write the answer: {0}")]
+    Output(#[source] BoxError),
+    #[error("no question provided")]
+    NoQuestion,
+    /// Invalid command line arguments, or a request for the help or the
+    /// version, which clap shows.
+    #[error("{0}")]
+    Usage(#[source] clap::Error),
+    /// The LLM responded without a reply, for the reason it gave.
+    #[error("{model} returned no reply: {reason}")]
+    NoReply { model: String, reason: String },
+    /// The run needs more LLM calls than `MAX_LLM_CALLS` allows.
+    #[error("this run needs more than MAX_LLM_CALLS={0} LLM calls, aborting")]
+    LlmCallLimit(u32),
+}
+
+/// Unreadable responses are parse errors and unreachable servers mean
+/// being offline, other errors belong to the stage. Errors which are
+/// classified already stay as they are.
+fn in_stage(e: BoxError, stage: fn(BoxError) -> WikiRagError) -> WikiRagError {
+    let e = match e.downcast::<WikiRagError>() {
+        Ok(e) => return *e,
+        Err(e) => e,
+    };
+    if is_connect_error(&*e) {
+        WikiRagError::Offline(e)
+    } else if e.is::<serde_json::Error>() {
+        WikiRagError::Parse(e)
+    } else {
+        stage(e)
+    }
+}
+
+impl WikiRagError {
+    pub fn search(e: BoxError) -> Self {
+        in_stage(e, WikiRagError::Search)
+    }
+
+    pub fn download(e: BoxError) -> Self {
+        in_stage(e, WikiRagError::Download)
+    }
+
+    pub fn llm(e: BoxError) -> Self {
+        if is_model_unavailable(&*e) {
+            return WikiRagError::ModelUnavailable(e);
+        }
+        in_stage(e, WikiRagError::Llm)
+    }
+
+    /// The exit code of the command line tool for the error. These are
+    /// all the codes it fails with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WikiRagError::Usage(e) if !e.use_stderr() => 0,
+            WikiRagError::Usage(_) => 1,
+            WikiRagError::Search(_) => 2,
+            WikiRagError::Download(_) => 3,
+            WikiRagError::Llm(_) => 4,
+            WikiRagError::Parse(_) => 5,
+            WikiRagError::Config(_) => 6,
+            WikiRagError::Offline(_) => 7,
+            WikiRagError::LlmCallLimit(_) => 8,
+            WikiRagError::Provider(_) => 9,
+            WikiRagError::ModelUnavailable(_) => 10,
+            WikiRagError::MissingApiKey(_) => 11,
+            WikiRagError::Output(_) => 12,
+            WikiRagError::NoQuestion => 13,
+            WikiRagError::NoReply { .. } => 14,
+        }
+    }
+}
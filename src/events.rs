@@ -0,0 +1,74 @@
+//! Typed events describing the progress of the pipeline. The command line
+//! tool renders them as its output; embedders can instead consume them as
+//! an async stream to build their own UIs or logs.
+
+use crate::{AnswerReport, Config, WikiPage};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    /// The LLM derived these keywords, in the order they will be tried.
+    KeywordsDerived { keywords: Vec<String> },
+    /// Wikipedia was searched with `keyword` and these are the candidates.
+    SearchCompleted {
+        keyword: String,
+        pages: Vec<WikiPage>,
+    },
+    PageDownloaded {
+        page_id: String,
+        title: String,
+        size: usize,
+    },
+    /// A piece of the answer text, in order.
+    AnswerChunk { text: String },
+    /// The answer to a question is complete.
+    Completed(AnswerReport),
+}
+
+/// A stream of pipeline events, see [`EventSink::stream`].
+pub type EventStream = UnboundedReceiver<PipelineEvent>;
+
+type Handler = Box<dyn FnMut(&Config, &PipelineEvent) + Send>;
+
+/// Where the pipeline delivers its events.
+pub enum EventSink {
+    /// Events are dropped.
+    None,
+    /// Events are handed to a callback synchronously, in pipeline order.
+    /// This is what the command line tool uses, so that its output stays
+    /// properly interleaved with prompts for user input.
+    Handler(Mutex<Handler>),
+    /// Events are sent into a channel and can be consumed as a stream.
+    Channel(UnboundedSender<PipelineEvent>),
+}
+
+impl EventSink {
+    pub fn handler(f: impl FnMut(&Config, &PipelineEvent) + Send + 'static) -> EventSink {
+        EventSink::Handler(Mutex::new(Box::new(f)))
+    }
+
+    /// Creates a sink together with the stream receiving its events. The
+    /// stream ends when the sink is dropped.
+    pub fn stream() -> (EventSink, EventStream) {
+        let (tx, rx) = unbounded();
+        (EventSink::Channel(tx), rx)
+    }
+
+    pub fn emit(&self, config: &Config, event: PipelineEvent) {
+        match self {
+            EventSink::None => {}
+            EventSink::Handler(h) => {
+                if let Ok(mut h) = h.lock() {
+                    h(config, &event);
+                }
+            }
+            EventSink::Channel(tx) => {
+                // A dropped receiver just means nobody is listening.
+                let _ = tx.unbounded_send(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! The section structure of a plain text extract. With `explaintext`
+//! the MediaWiki API marks headings as `== Heading ==` lines, so the
+//! intro and the sections can be told apart and cut separately.
+
+use crate::{cut_at_paragraph, significant_words};
+
+/// A section of an article, with its heading level: 2 for `== h ==`,
+/// 3 for `=== h ===` and so on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub level: usize,
+    pub heading: String,
+    pub body: String,
+}
+
+/// A page extract split into the intro and the sections after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WikiExtract {
+    pub intro: String,
+    pub sections: Vec<Section>,
+}
+
+/// The level and text of a heading line, if it is one.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim();
+    let level = line.chars().take_while(|&c| c == '=').count();
+    if level < 2 || !line.ends_with(&"=".repeat(level)) || line.len() <= 2 * level {
+        return None;
+    }
+    let text = line[level..line.len() - level].trim();
+    (!text.is_empty()).then_some((level, text))
+}
+
+impl WikiExtract {
+    pub fn parse(text: &str) -> Self {
+        let mut intro = String::new();
+        let mut sections: Vec<Section> = vec![];
+        for line in text.lines() {
+            if let Some((level, h)) = heading(line) {
+                sections.push(Section {
+                    level,
+                    heading: h.to_string(),
+                    body: String::new(),
+                });
+                continue;
+            }
+            let body = match sections.last_mut() {
+                Some(s) => &mut s.body,
+                None => &mut intro,
+            };
+            body.push_str(line);
+            body.push('\n');
+        }
+        for s in sections.iter_mut() {
+            s.body = s.body.trim().to_string();
+        }
+        WikiExtract {
+            intro: intro.trim().to_string(),
+            sections,
+        }
+    }
+
+    /// The extract as text again, with the headings marked as before.
+    pub fn render(&self) -> String {
+        render(&self.intro, self.sections.iter())
+    }
+
+    /// The intro and as many sections as fit into `max` bytes. Sections
+    /// which mention words of `keywords` in their heading or body come
+    /// first, among equally relevant ones the earlier, so trailing
+    /// sections are dropped first. Sections without text are left out,
+    /// and an intro which alone is too long is cut at a paragraph.
+    pub fn fit(&self, max: usize, keywords: &str) -> String {
+        let full = self.render();
+        if full.len() <= max {
+            return full;
+        }
+        if self.intro.len() >= max {
+            return cut_at_paragraph(&self.intro, max).to_string();
+        }
+        let words = significant_words(keywords);
+        let relevance = |s: &Section| {
+            let heading = s.heading.to_lowercase();
+            let body = s.body.to_lowercase();
+            words
+                .iter()
+                .map(|w| 5 * heading.matches(w.as_str()).count() + body.matches(w.as_str()).count())
+                .sum::<usize>()
+        };
+        let mut order: Vec<usize> = (0..self.sections.len())
+            .filter(|&i| !self.sections[i].body.is_empty())
+            .collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(relevance(&self.sections[i])));
+
+        let mut room = max - self.intro.len();
+        let mut keep = vec![false; self.sections.len()];
+        for i in order {
+            let len = rendered_len(&self.sections[i]);
+            if len <= room {
+                keep[i] = true;
+                room -= len;
+            }
+        }
+        let kept = self.sections.iter().zip(keep).filter(|(_, k)| *k);
+        render(&self.intro, kept.map(|(s, _)| s))
+    }
+}
+
+fn render<'a>(intro: &str, sections: impl Iterator<Item = &'a Section>) -> String {
+    let mut text = intro.to_string();
+    for s in sections {
+        let marks = "=".repeat(s.level);
+        text.push_str(&format!(
+            "\n\n{} {} {}\n{}",
+            marks, s.heading, marks, s.body
+        ));
+    }
+    text
+}
+
+fn rendered_len(s: &Section) -> usize {
+    // Two line breaks, the marks with their spaces, and one line break:
+    2 + 2 * (s.level + 1) + s.heading.len() + 1 + s.body.len()
+}
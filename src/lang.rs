@@ -0,0 +1,98 @@
+//! Minimal language handling: guessing the language of a question and
+//! naming Wikipedia language codes for prompts.
+
+/// A few very frequent words per language, enough to tell the languages
+/// apart in a typical question.
+const COMMON_WORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "is", "are", "was", "what", "who", "how", "why", "when", "where", "which", "of",
+            "and", "did", "does", "in",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "ist", "sind", "war", "was", "wer", "wie", "warum", "wann", "wo",
+            "und", "ein", "eine", "hat",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "est", "sont", "qui", "que", "quoi", "comment", "pourquoi", "quand",
+            "où", "et", "un", "une", "des",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "las", "es", "son", "qué", "quién", "cómo", "por", "cuándo",
+            "dónde", "y", "un", "una", "fue",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "lo", "la", "gli", "è", "sono", "chi", "che", "come", "perché", "quando", "dove",
+            "e", "un", "una", "era",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "is", "zijn", "wie", "wat", "hoe", "waarom", "wanneer", "waar",
+            "en", "van", "was", "heeft",
+        ],
+    ),
+];
+
+/// Guesses the language of `text` by counting common words. Returns the
+/// Wikipedia language code, or `None` if no language clearly wins.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let mut best: Option<(&'static str, usize)> = None;
+    let mut tie = false;
+    for (lang, common) in COMMON_WORDS {
+        let hits = words
+            .iter()
+            .filter(|w| common.contains(&w.as_str()))
+            .count();
+        match best {
+            Some((_, n)) if hits == n => tie = true,
+            Some((_, n)) if hits < n => {}
+            _ => {
+                best = Some((lang, hits));
+                tie = false;
+            }
+        }
+    }
+    match best {
+        Some((lang, n)) if n > 0 && !tie => Some(lang),
+        _ => None,
+    }
+}
+
+/// The English name of a Wikipedia language code, for use in prompts.
+pub fn language_name(code: &str) -> &str {
+    match code {
+        "en" => "English",
+        "de" => "German",
+        "fr" => "French",
+        "es" => "Spanish",
+        "it" => "Italian",
+        "nl" => "Dutch",
+        "pt" => "Portuguese",
+        "pl" => "Polish",
+        "sv" => "Swedish",
+        "ru" => "Russian",
+        "ja" => "Japanese",
+        "zh" => "Chinese",
+        _ => code,
+    }
+}
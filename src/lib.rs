@@ -0,0 +1,5424 @@
+//! WikiRag answers questions with the help of an LLM and Wikipedia: the
+//! LLM derives keywords, which are used to search Wikipedia, and the
+//! retrieved pages are then given to the LLM to answer the question.
+//!
+//! The pipeline reports its progress as [`events::PipelineEvent`]s, which
+//! the command line tool renders and embedders can consume as a stream.
+
+use async_openai::types::{CompletionUsage, CreateChatCompletionResponse};
+use clap::builder::FalseyValueParser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use regex::Regex;
+use reqwest::Client as ReqClient;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+mod audit;
+mod cache;
+mod entities;
+pub mod error;
+pub mod events;
+mod extract;
+mod lang;
+pub mod llm;
+mod local;
+mod ratelimit;
+mod retry;
+pub mod session;
+mod stopwords;
+#[cfg(test)]
+mod tests;
+mod usage_log;
+
+pub use error::WikiRagError;
+use events::{EventSink, PipelineEvent};
+use llm::LlmBackend;
+use ratelimit::RateLimiter;
+
+/// Prints progress information to stderr, unless the configured output
+/// format needs a clean stream (for example JSON).
+#[macro_export]
+macro_rules! progress {
+    ($config:expr, $($arg:tt)*) => {
+        if $config.chatty() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a prompt asking the user for input to stderr, if prompts are
+/// wanted (by default only when stdin is a terminal).
+#[macro_export]
+macro_rules! prompt {
+    ($config:expr, $($arg:tt)*) => {
+        if $config.show_prompt() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LlmProvider {
+    OpenAI,
+    Ollama,
+    Anthropic,
+}
+
+/// How the answer is produced from several pages.
+#[derive(PartialEq)]
+pub enum AnswerMode {
+    /// All pages go into a single answer request.
+    Concat,
+    /// Each page is answered on its own, then the answers are combined.
+    MapReduce,
+}
+
+/// When to print prompts asking for input.
+pub enum PromptMode {
+    /// Only when stdin is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl PromptMode {
+    /// Whether prompts are shown, given whether stdin is a terminal.
+    pub fn shows_prompt(&self, stdin_is_terminal: bool) -> bool {
+        match self {
+            PromptMode::Auto => stdin_is_terminal,
+            PromptMode::Always => true,
+            PromptMode::Never => false,
+        }
+    }
+}
+
+/// How the pages to download are chosen among the search results.
+#[derive(PartialEq)]
+pub enum PageSelection {
+    /// Take the top search results.
+    Search,
+    /// Let the LLM pick based on titles and snippets.
+    Llm,
+}
+
+/// How the search keywords are derived from the question.
+#[derive(PartialEq)]
+pub enum KeywordMethod {
+    Llm,
+    /// The question without its stopwords, no LLM call.
+    Local,
+}
+
+/// How Wikipedia is searched for candidate pages.
+pub enum SearchMode {
+    /// Full text search for a keyword.
+    Keyword,
+    /// The pages of a category named by the LLM.
+    Category,
+}
+
+/// The audience the answer is written for.
+pub enum ReadingLevel {
+    Child,
+    Teen,
+    /// A neutral general audience, no extra instruction.
+    General,
+    Expert,
+}
+
+impl ReadingLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadingLevel::Child => "child",
+            ReadingLevel::Teen => "teen",
+            ReadingLevel::General => "general",
+            ReadingLevel::Expert => "expert",
+        }
+    }
+
+    fn instruction(&self) -> Option<&'static str> {
+        match self {
+            ReadingLevel::Child => Some("Write for a ten year old child: use short sentences and simple everyday words, explain every technical term and leave out details which are not needed to understand the answer."),
+            ReadingLevel::Teen => Some("Write for a teenager in secondary school: use plain language, briefly explain technical terms and give the most important background."),
+            ReadingLevel::General => None,
+            ReadingLevel::Expert => Some("Write for an expert in the field: use the precise technical vocabulary without explaining basics, and include relevant details, figures and nuances."),
+        }
+    }
+}
+
+/// How the sources are listed below text and markdown answers.
+pub enum CitationStyle {
+    /// Just the links.
+    Plain,
+    Bibtex,
+    Apa,
+}
+
+/// A column of the search results table.
+#[derive(Clone, Copy)]
+pub enum TableColumn {
+    PageId,
+    Title,
+    Url,
+    Snippet,
+    Size,
+    WordCount,
+    /// Position in the search results.
+    Rank,
+}
+
+pub const DEFAULT_TABLE_COLUMNS: [TableColumn; 4] = [
+    TableColumn::PageId,
+    TableColumn::Title,
+    TableColumn::Snippet,
+    TableColumn::Url,
+];
+
+/// Cuts a text to at most `width` characters, at a word boundary and
+/// with an ellipsis if anything is left out.
+fn cut_to_width(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let head: String = text.chars().take(width - 3).collect();
+    let head = match head.rfind(' ') {
+        Some(pos) if pos >= head.len() / 2 => &head[..pos],
+        _ => head.as_str(),
+    };
+    format!("{}...", head.trim_end())
+}
+
+impl TableColumn {
+    fn parse(name: &str) -> Option<TableColumn> {
+        match name {
+            "id" | "pageid" => Some(TableColumn::PageId),
+            "title" => Some(TableColumn::Title),
+            "url" => Some(TableColumn::Url),
+            "snippet" => Some(TableColumn::Snippet),
+            "size" => Some(TableColumn::Size),
+            "wordcount" => Some(TableColumn::WordCount),
+            "rank" => Some(TableColumn::Rank),
+            _ => None,
+        }
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            TableColumn::PageId => "page id",
+            TableColumn::Title => "title",
+            TableColumn::Url => "URL",
+            TableColumn::Snippet => "snippet",
+            TableColumn::Size => "size",
+            TableColumn::WordCount => "words",
+            TableColumn::Rank => "rank",
+        }
+    }
+
+    /// Pads or cuts a text to the width of the column.
+    pub fn pad(&self, text: &str) -> String {
+        match self {
+            TableColumn::PageId => format!("{:>10}", text),
+            TableColumn::Title => format!("{:<30}", cut_to_width(text, 30)),
+            TableColumn::Url => text.to_string(),
+            TableColumn::Snippet => format!("{:<50}", cut_to_width(text, 50)),
+            TableColumn::Size | TableColumn::WordCount => format!("{:>9}", text),
+            TableColumn::Rank => format!("{:>4}", text),
+        }
+    }
+
+    /// The cell for the page at position `rank` (from 1) of the results.
+    pub fn cell(&self, language: &str, rank: usize, page: &WikiPage) -> String {
+        let text = match self {
+            TableColumn::PageId => page.page_id.clone(),
+            TableColumn::Title => page.title.clone(),
+            TableColumn::Url => wiki_url(language, &page.title),
+            TableColumn::Snippet => strip_html_tags(&page.snippet),
+            TableColumn::Size => page.size.map(|s| s.to_string()).unwrap_or_default(),
+            TableColumn::WordCount => page.wordcount.map(|w| w.to_string()).unwrap_or_default(),
+            TableColumn::Rank => rank.to_string(),
+        };
+        self.pad(&text)
+    }
+}
+
+/// Parses a comma-separated list of table columns. Any unknown column
+/// name makes it fall back to the default columns.
+fn parse_table_columns(val: &str) -> Vec<TableColumn> {
+    let names: Vec<&str> = val
+        .split(',')
+        .map(|n| n.trim())
+        .filter(|n| !n.is_empty())
+        .collect();
+    let columns: Option<Vec<TableColumn>> = names.iter().map(|n| TableColumn::parse(n)).collect();
+    match columns {
+        Some(columns) if !columns.is_empty() => columns,
+        _ => {
+            eprintln!(
+                "Invalid TABLE_COLUMNS {}, using the default. Allowed columns are: id, title, url, snippet, size, wordcount, rank",
+                val
+            );
+            DEFAULT_TABLE_COLUMNS.to_vec()
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Markdown,
+    Json,
+}
+
+pub struct Config {
+    pub model: String,
+    pub verbose: bool,
+    /// No progress output, only the answer and errors.
+    pub quiet: bool,
+    pub wiki_pages: u32,
+    pub llm_server: LlmProvider,
+    pub format: OutputFormat,
+    pub skip_title_patterns: Vec<Regex>,
+    pub interactive: bool,
+    pub language: String,
+    pub rate_limiter: RateLimiter,
+    /// Client for all requests, to Wikipedia and the LLM providers, shared
+    /// for its connection pool, see [`Config::http_timeout`] and
+    /// [`Config::user_agent`].
+    pub http: ReqClient,
+    pub http_timeout: Duration,
+    /// The MediaWiki API endpoint, if not the Wikipedia of the language.
+    pub wiki_api: Option<String>,
+    /// Directory of page extracts used instead of Wikipedia, see
+    /// [`local`].
+    pub local_source: Option<std::path::PathBuf>,
+    /// Download only the lead section of every page.
+    pub intro_only: bool,
+    /// Templates replacing the built-in keyword system prompt and answer
+    /// instruction, see [`fill_template`].
+    pub keyword_prompt: Option<String>,
+    pub answer_prompt: Option<String>,
+    /// Identifies us to Wikipedia, as its API policy asks for.
+    pub user_agent: String,
+    pub output_file: Option<String>,
+    /// File the interactive conversation is saved to and resumed from.
+    pub session_file: Option<String>,
+    /// Directory receiving the raw request and response of every LLM call.
+    pub audit_dir: Option<String>,
+    /// CSV file every LLM call appends its tokens and cost to.
+    pub usage_log: Option<String>,
+    /// Show the answer request instead of sending it.
+    pub dry_run: bool,
+    pub answer_mode: AnswerMode,
+    /// Number of keywords searched together, for questions about several
+    /// topics.
+    pub keywords: u32,
+    pub keyword_alternatives: u32,
+    pub fallback_keywords: Vec<String>,
+    /// How often the LLM is asked for a broader keyword when no search
+    /// found anything usable.
+    pub broaden_retries: u32,
+    pub extractive: bool,
+    pub prompt_mode: PromptMode,
+    /// Keyword extraction should be deterministic, so it gets its own
+    /// temperature (default 0), while answers may benefit from a little
+    /// more variety for fluency.
+    pub keyword_temperature: f32,
+    /// `None` leaves the provider's default.
+    pub answer_temperature: Option<f32>,
+    pub last_edited: bool,
+    pub select: PageSelection,
+    pub keyword_entity_hint: bool,
+    /// Stop downloading further pages once this many bytes of extracts
+    /// have been collected.
+    pub max_total_download_bytes: Option<usize>,
+    /// Fail when a selected page cannot be downloaded, instead of using
+    /// the next search result.
+    pub strict_downloads: bool,
+    /// Number of search results to ask for, see [`Config::search_limit`].
+    pub search_limit: Option<usize>,
+    /// Use the default model when the configured one is not available.
+    pub model_fallback: bool,
+    /// Keep downloaded extracts in a file cache, see [`cache`].
+    pub page_cache: bool,
+    /// Days after which a cached extract is downloaded again.
+    pub cache_ttl_days: u64,
+    /// Cut long pages by whole sections, see [`extract::WikiExtract`].
+    pub section_aware: bool,
+    pub search_mode: SearchMode,
+    /// Overrides the context window of the model, in tokens.
+    pub context_tokens: Option<u32>,
+    /// How often a request failing transiently is repeated.
+    pub max_retries: u32,
+    /// Number of messages of a conversation which are sent to Ollama.
+    /// More messages keep earlier questions and answers in context, but
+    /// every kept message is sent again with each follow-up, which takes
+    /// memory and room in the local model's context window next to the
+    /// page extracts.
+    pub ollama_history_size: u16,
+    /// Template of a one-line provenance footer appended to text and
+    /// markdown answers, see [`DEFAULT_FOOTER`] for the placeholders.
+    pub answer_footer: Option<String>,
+    /// How many pages linked from the first downloaded page are added as
+    /// additional context, 0 disables this.
+    pub include_linked: u32,
+    /// Answers longer than this many words are compressed by a second
+    /// LLM call.
+    pub answer_max_words: Option<usize>,
+    /// Token limit of the answer call.
+    pub answer_max_tokens: u32,
+    /// Summarize every downloaded page to about this many characters
+    /// before answering, to fit more pages into the context.
+    pub compact_context: Option<usize>,
+    /// Language codes to translate the answer into, each is shown in a
+    /// section of its own.
+    pub answer_langs: Vec<String>,
+    /// JSON schema the answer must conform to, the answer is then a JSON
+    /// document instead of prose.
+    pub answer_schema: Option<serde_json::Value>,
+    /// Why the `ANSWER_SCHEMA` could not be loaded, see
+    /// [`validate_answer_schema`].
+    pub answer_schema_error: Option<String>,
+    /// Additional search terms for phrases in a keyword, keys are lower
+    /// case.
+    pub synonyms: HashMap<String, Vec<String>>,
+    pub reading_level: ReadingLevel,
+    /// Prefer candidates with a better Wikipedia quality assessment.
+    pub prefer_quality: bool,
+    /// Only give the LLM the intro of every page and windows of this many
+    /// characters around the keywords.
+    pub context_window_chars: Option<usize>,
+    /// Trust weights for sources whose title matches a pattern, so that
+    /// the answer prefers more trusted sources on conflicts.
+    pub source_trust: Vec<(Regex, u32)>,
+    /// Show the source passages supporting the claims of the answer.
+    pub explain: bool,
+    pub table_columns: Vec<TableColumn>,
+    /// Answer from exactly one page, the best candidate, whatever
+    /// `wiki_pages` says.
+    pub best_page_only: bool,
+    pub keyword_method: KeywordMethod,
+    /// Stopwords for the local keyword method, replacing the built-in
+    /// list of the question's language.
+    pub stopwords: Option<Vec<String>>,
+    /// Deliver the answer in pieces as the LLM generates it. On by
+    /// default when stdout is a terminal.
+    pub stream: bool,
+    /// How often an interrupted answer stream is retried from scratch.
+    pub stream_retries: u32,
+    /// Rewrite the question into a search query before deriving keywords.
+    pub rewrite_question: bool,
+    /// Download pages one at a time and stop as soon as the LLM says they
+    /// suffice for an answer.
+    pub adaptive_context: bool,
+    /// Rate how well the pages support the answer, with an extra call.
+    pub with_confidence: bool,
+    /// Split compound questions into sub-questions and retrieve for each.
+    pub decompose: bool,
+    pub citation_style: CitationStyle,
+    /// Sort the candidates by embedding similarity to the question.
+    pub rerank: bool,
+    /// Prefer candidates whose titles name the entities of the question.
+    pub entity_boost: bool,
+    /// Candidates less similar than this are dropped when reranking.
+    pub rerank_min_similarity: Option<f64>,
+    /// Overrides the provider's default embedding model.
+    pub embedding_model: Option<String>,
+    /// Computes the embeddings instead of the provider of the model.
+    pub embedding_provider: Option<LlmProvider>,
+    /// Safety cap on the number of LLM calls in a run.
+    pub max_llm_calls: Option<u32>,
+    pub usage: TokenUsage,
+    /// Receives the progress events of the pipeline.
+    pub events: EventSink,
+}
+
+impl Config {
+    fn emit(&self, event: PipelineEvent) {
+        self.events.emit(self, event);
+    }
+}
+
+impl Config {
+    /// How many pages are downloaded to answer from.
+    pub fn pages_wanted(&self) -> usize {
+        if self.best_page_only {
+            1
+        } else {
+            self.wiki_pages as usize
+        }
+    }
+
+    /// How many results a search asks for: enough to replace skipped
+    /// pages, and a full page of candidates when they are ranked again.
+    pub fn search_limit(&self) -> usize {
+        let mut limit = self.pages_wanted() + SEARCH_MARGIN;
+        if self.rerank
+            || self.entity_boost
+            || self.prefer_quality
+            || self.select == PageSelection::Llm
+        {
+            limit = limit.max(RANKING_CANDIDATES);
+        }
+        if let Some(n) = self.search_limit {
+            limit = n.max(self.pages_wanted());
+        }
+        limit.min(MAX_SEARCH_LIMIT)
+    }
+
+    /// Whether informational progress output should go to stderr.
+    pub fn chatty(&self) -> bool {
+        self.format != OutputFormat::Json && !self.quiet
+    }
+
+    /// Whether the answer is streamed. An answer with a schema is only
+    /// shown after it was validated, so it is never streamed.
+    pub fn streams(&self) -> bool {
+        self.stream && self.answer_schema.is_none()
+    }
+
+    /// Whether prompts for user input should be shown. With redirected
+    /// input nobody reads them, so by default they are suppressed then.
+    pub fn show_prompt(&self) -> bool {
+        self.prompt_mode.shows_prompt(io::stdin().is_terminal())
+    }
+}
+
+/// The model used when none is configured.
+const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+
+/// How long a request to Wikipedia or an LLM provider may take, unless
+/// set with `HTTP_TIMEOUT` in seconds.
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_user_agent() -> String {
+    format!(
+        "wikirag/{} (https://github.com/neunhoef/wikirag)",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn http_client(timeout: Duration, user_agent: &str) -> ReqClient {
+    ReqClient::builder()
+        .timeout(timeout)
+        .user_agent(user_agent)
+        .build()
+        .expect("could not set up the HTTP client")
+}
+
+/// Models known to work well, suggested when the providers cannot be
+/// asked for their models.
+const MODELS: &[(&str, LlmProvider)] = &[
+    ("gpt-4-turbo", LlmProvider::OpenAI),
+    ("gpt-4o", LlmProvider::OpenAI),
+    ("gpt-3.5-turbo", LlmProvider::OpenAI),
+    ("llama3", LlmProvider::Ollama),
+    ("claude-3-5-sonnet", LlmProvider::Anthropic),
+    ("claude-3-haiku", LlmProvider::Anthropic),
+];
+
+/// The provider serving a model, by the name: OpenAI and Anthropic
+/// models have their prefixes, everything else is taken to be a local
+/// Ollama model. Whether the provider really has the model is checked
+/// against its model list, see [`validate_providers`].
+pub fn provider_for_model(model: &str) -> Option<LlmProvider> {
+    if let Some((_, provider)) = MODELS.iter().find(|(name, _)| *name == model) {
+        return Some(*provider);
+    }
+    if model.is_empty() || model.contains(char::is_whitespace) {
+        return None;
+    }
+    if ["gpt-", "chatgpt-", "o1", "o3", "o4"]
+        .iter()
+        .any(|p| model.starts_with(p))
+    {
+        Some(LlmProvider::OpenAI)
+    } else if model.starts_with("claude-") {
+        Some(LlmProvider::Anthropic)
+    } else {
+        Some(LlmProvider::Ollama)
+    }
+}
+
+/// The model lists of the providers, fetched once per process.
+static OPENAI_MODELS: tokio::sync::OnceCell<Vec<String>> = tokio::sync::OnceCell::const_new();
+static OLLAMA_MODELS: tokio::sync::OnceCell<Vec<String>> = tokio::sync::OnceCell::const_new();
+static ANTHROPIC_MODELS: tokio::sync::OnceCell<Vec<String>> = tokio::sync::OnceCell::const_new();
+
+fn model_cache(provider: LlmProvider) -> &'static tokio::sync::OnceCell<Vec<String>> {
+    match provider {
+        LlmProvider::OpenAI => &OPENAI_MODELS,
+        LlmProvider::Ollama => &OLLAMA_MODELS,
+        LlmProvider::Anthropic => &ANTHROPIC_MODELS,
+    }
+}
+
+/// The models a provider offers, as it lists them.
+pub async fn available_models(
+    config: &Config,
+    provider: LlmProvider,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let models = model_cache(provider)
+        .get_or_try_init(|| async move {
+            let mut models = match provider {
+                LlmProvider::OpenAI => llm::openai_client(config)
+                    .models()
+                    .list()
+                    .await?
+                    .data
+                    .into_iter()
+                    .map(|m| m.id)
+                    .collect(),
+                LlmProvider::Ollama => ollama_models(config).await?,
+                LlmProvider::Anthropic => llm::anthropic_models(config).await?,
+            };
+            models.sort();
+            Ok::<_, Box<dyn std::error::Error>>(models)
+        })
+        .await?;
+    Ok(models.clone())
+}
+
+/// Whether a model list has the model. Ollama lists models with their
+/// tag, and Anthropic lists dated versions of the `-latest` aliases.
+fn lists_model(models: &[String], provider: LlmProvider, model: &str) -> bool {
+    match provider {
+        LlmProvider::OpenAI => models.iter().any(|m| m == model),
+        LlmProvider::Ollama => models
+            .iter()
+            .any(|m| m == model || m.split(':').next() == Some(model)),
+        LlmProvider::Anthropic => {
+            let id = llm::anthropic_model_id(model);
+            let base = id.trim_end_matches("-latest");
+            models.iter().any(|m| m == id || m.starts_with(base))
+        }
+    }
+}
+
+/// The models to suggest as a list with one model per line: those the
+/// providers listed so far, or the known good ones.
+fn supported_models() -> String {
+    let listed: Vec<String> = [
+        LlmProvider::OpenAI,
+        LlmProvider::Anthropic,
+        LlmProvider::Ollama,
+    ]
+    .into_iter()
+    .filter_map(|p| model_cache(p).get())
+    .flatten()
+    .cloned()
+    .collect();
+    if listed.is_empty() {
+        MODELS
+            .iter()
+            .map(|(name, _)| format!("  - {}\n", name))
+            .collect()
+    } else {
+        listed
+            .iter()
+            .map(|name| format!("  - {}\n", name))
+            .collect()
+    }
+}
+
+pub fn get_config_from_env() -> Config {
+    let mut c = default_config();
+    apply_settings(&mut c, &|name| std::env::var(name));
+    warn_unknown_context_window(&c);
+    c
+}
+
+/// The configuration without any settings applied.
+fn default_config() -> Config {
+    Config {
+        model: DEFAULT_MODEL.into(),
+        verbose: false,
+        quiet: false,
+        wiki_pages: 1,
+        llm_server: LlmProvider::OpenAI,
+        format: OutputFormat::Text,
+        skip_title_patterns: vec![],
+        interactive: false,
+        language: "en".into(),
+        rate_limiter: RateLimiter::new(None, None),
+        http: http_client(DEFAULT_HTTP_TIMEOUT, &default_user_agent()),
+        http_timeout: DEFAULT_HTTP_TIMEOUT,
+        wiki_api: None,
+        local_source: None,
+        intro_only: false,
+        keyword_prompt: None,
+        answer_prompt: None,
+        user_agent: default_user_agent(),
+        output_file: None,
+        session_file: None,
+        audit_dir: None,
+        usage_log: None,
+        dry_run: false,
+        answer_mode: AnswerMode::Concat,
+        keywords: 3,
+        keyword_alternatives: 0,
+        broaden_retries: 1,
+        fallback_keywords: vec![],
+        extractive: false,
+        prompt_mode: PromptMode::Auto,
+        keyword_temperature: 0.0,
+        answer_temperature: None,
+        last_edited: false,
+        select: PageSelection::Search,
+        keyword_entity_hint: false,
+        max_total_download_bytes: None,
+        strict_downloads: false,
+        search_limit: None,
+        model_fallback: false,
+        page_cache: true,
+        cache_ttl_days: 7,
+        section_aware: false,
+        search_mode: SearchMode::Keyword,
+        context_tokens: None,
+        max_retries: 3,
+        ollama_history_size: 30,
+        answer_footer: None,
+        include_linked: 0,
+        answer_max_words: None,
+        answer_max_tokens: DEFAULT_ANSWER_MAX_TOKENS,
+        compact_context: None,
+        answer_langs: vec![],
+        answer_schema: None,
+        answer_schema_error: None,
+        synonyms: HashMap::new(),
+        reading_level: ReadingLevel::General,
+        prefer_quality: false,
+        context_window_chars: None,
+        source_trust: vec![],
+        explain: false,
+        table_columns: DEFAULT_TABLE_COLUMNS.to_vec(),
+        best_page_only: false,
+        keyword_method: KeywordMethod::Llm,
+        stopwords: None,
+        // Streaming is for people watching the answer arrive:
+        stream: io::stdout().is_terminal(),
+        stream_retries: 0,
+        rewrite_question: false,
+        with_confidence: false,
+        adaptive_context: false,
+        decompose: false,
+        citation_style: CitationStyle::Plain,
+        rerank: false,
+        entity_boost: false,
+        rerank_min_similarity: None,
+        embedding_model: None,
+        embedding_provider: None,
+        max_llm_calls: None,
+        usage: TokenUsage::default(),
+        events: EventSink::None,
+    }
+}
+
+/// Whether the value of a boolean environment variable switches it on:
+/// anything but empty, "0", "false", "no" and "off".
+fn env_flag(val: &str) -> bool {
+    !matches!(
+        val.trim().to_lowercase().as_str(),
+        "" | "0" | "false" | "no" | "off"
+    )
+}
+
+/// Applies the settings named like the environment variables, as found by
+/// `var`. Settings which are not present are left alone.
+fn apply_settings(c: &mut Config, var: &dyn Fn(&str) -> Result<String, std::env::VarError>) {
+    if let Ok(val) = var("AI_MODEL") {
+        match provider_for_model(&val) {
+            Some(provider) => {
+                c.model = val;
+                c.llm_server = provider;
+            }
+            None => {
+                eprintln!(
+                    "Invalid model name '{}', using '{}'. Models known to work are:
+{}",
+                    val,
+                    DEFAULT_MODEL,
+                    supported_models()
+                );
+            }
+        }
+    }
+    if let Ok(val) = var("MODEL_FALLBACK") {
+        c.model_fallback = env_flag(&val);
+    }
+    if let Ok(val) = var("VERBOSE") {
+        c.verbose = env_flag(&val);
+    }
+    if let Ok(val) = var("QUIET") {
+        c.quiet = env_flag(&val);
+    }
+    if let Ok(val) = var("WIKI_PAGES") {
+        if !val.is_empty() {
+            let n = val.parse::<u32>();
+            if let Ok(n) = n {
+                c.wiki_pages = n;
+                if c.wiki_pages == 0 {
+                    c.wiki_pages = 1;
+                }
+            }
+        }
+    }
+    if let Ok(val) = var("ANSWER_MAX_TOKENS") {
+        match val.parse::<u32>() {
+            Ok(n) if n >= MIN_ANSWER_TOKENS => c.answer_max_tokens = n,
+            _ => eprintln!(
+                "Ignoring invalid ANSWER_MAX_TOKENS {}, it must be at least {}",
+                val, MIN_ANSWER_TOKENS
+            ),
+        }
+    }
+    if let Ok(val) = var("INTERACTIVE") {
+        c.interactive = env_flag(&val);
+    }
+    if let Ok(val) = var("WIKI_LANG") {
+        if !val.is_empty() {
+            c.language = parse_language(&val);
+        }
+    }
+    if let Ok(val) = var("ANSWER_LANGS") {
+        c.answer_langs = vec![];
+        for code in val.split(',').map(|l| l.trim().to_lowercase()) {
+            if (2..=3).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                if !c.answer_langs.contains(&code) {
+                    c.answer_langs.push(code);
+                }
+            } else if !code.is_empty() {
+                eprintln!(
+                    "Ignoring invalid answer language '{}' in ANSWER_LANGS",
+                    code
+                );
+            }
+        }
+    }
+    if let Ok(val) = var("ANSWER_MODE") {
+        match val.as_ref() {
+            "" | "concat" => c.answer_mode = AnswerMode::Concat,
+            "mapreduce" => c.answer_mode = AnswerMode::MapReduce,
+            _ => eprintln!(
+                "Unknown answer mode {}, using 'concat'. Allowed modes are: concat, mapreduce",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("KEYWORDS") {
+        match val.parse::<u32>() {
+            Ok(n) if n > 0 => c.keywords = n,
+            _ => eprintln!("Ignoring invalid KEYWORDS {}, it must be at least 1", val),
+        }
+    }
+    if let Ok(val) = var("KEYWORD_ALTERNATIVES") {
+        if let Ok(n) = val.parse::<u32>() {
+            c.keyword_alternatives = n;
+        }
+    }
+    if let Ok(val) = var("BROADEN_RETRIES") {
+        match val.parse::<u32>() {
+            Ok(n) => c.broaden_retries = n,
+            Err(_) => eprintln!("Ignoring invalid BROADEN_RETRIES {}", val),
+        }
+    }
+    if let Ok(val) = var("KEYWORD_ENTITY_HINT") {
+        c.keyword_entity_hint = env_flag(&val);
+    }
+    if let Ok(val) = var("FALLBACK_KEYWORDS") {
+        c.fallback_keywords = val
+            .split(',')
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty())
+            .collect();
+    }
+    if let Ok(val) = var("EXTRACTIVE") {
+        c.extractive = env_flag(&val);
+    }
+    if let Ok(val) = var("SHOW_PROMPT") {
+        match val.as_ref() {
+            "" | "auto" => c.prompt_mode = PromptMode::Auto,
+            "always" => c.prompt_mode = PromptMode::Always,
+            "never" => c.prompt_mode = PromptMode::Never,
+            _ => eprintln!(
+                "Unknown SHOW_PROMPT value {}, using 'auto'. Allowed values are: auto, always, never",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("KEYWORD_TEMPERATURE") {
+        match val.parse::<f32>() {
+            Ok(t) => c.keyword_temperature = t,
+            Err(_) => eprintln!("Ignoring invalid KEYWORD_TEMPERATURE {}", val),
+        }
+    }
+    if let Ok(val) = var("ANSWER_TEMPERATURE") {
+        match val.parse::<f32>() {
+            Ok(t) => c.answer_temperature = Some(t),
+            Err(_) => eprintln!("Ignoring invalid ANSWER_TEMPERATURE {}", val),
+        }
+    }
+    if let Ok(val) = var("LAST_EDITED") {
+        c.last_edited = env_flag(&val);
+    }
+    if let Ok(val) = var("SELECT") {
+        match val.as_ref() {
+            "" | "search" => c.select = PageSelection::Search,
+            "llm" => c.select = PageSelection::Llm,
+            _ => eprintln!(
+                "Unknown page selection {}, using 'search'. Allowed values are: search, llm",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("MAX_TOTAL_DOWNLOAD_BYTES") {
+        match val.parse::<usize>() {
+            Ok(n) if n > 0 => c.max_total_download_bytes = Some(n),
+            _ => eprintln!("Ignoring invalid MAX_TOTAL_DOWNLOAD_BYTES {}", val),
+        }
+    }
+    if let Ok(val) = var("SEARCH_LIMIT") {
+        match val.parse::<usize>() {
+            Ok(n) if n > 0 => c.search_limit = Some(n),
+            _ => eprintln!("Ignoring invalid SEARCH_LIMIT {}", val),
+        }
+    }
+    if let Ok(val) = var("STRICT_DOWNLOADS") {
+        c.strict_downloads = env_flag(&val);
+    }
+    if let Ok(val) = var("SECTIONS") {
+        c.section_aware = env_flag(&val);
+    }
+    if let Ok(val) = var("NO_CACHE") {
+        c.page_cache = !env_flag(&val);
+    }
+    if let Ok(val) = var("CACHE_TTL_DAYS") {
+        match val.parse::<u64>() {
+            Ok(n) => c.cache_ttl_days = n,
+            Err(_) => eprintln!("Ignoring invalid CACHE_TTL_DAYS {}", val),
+        }
+    }
+    if let Ok(val) = var("MODEL_CONTEXT_TOKENS") {
+        match val.parse::<u32>() {
+            Ok(n) if n > 0 => c.context_tokens = Some(n),
+            _ => eprintln!("Ignoring invalid MODEL_CONTEXT_TOKENS {}", val),
+        }
+    }
+    if let Ok(val) = var("MAX_RETRIES") {
+        match val.parse::<u32>() {
+            Ok(n) => c.max_retries = n,
+            Err(_) => eprintln!("Ignoring invalid MAX_RETRIES {}", val),
+        }
+    }
+    if let Ok(val) = var("OLLAMA_HISTORY_SIZE") {
+        match val.parse::<u16>() {
+            Ok(n) if n >= 2 => c.ollama_history_size = n,
+            _ => eprintln!(
+                "Ignoring invalid OLLAMA_HISTORY_SIZE {}, it must be at least 2",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("SEARCH_MODE") {
+        match val.as_ref() {
+            "" | "keyword" => c.search_mode = SearchMode::Keyword,
+            "category" => c.search_mode = SearchMode::Category,
+            _ => eprintln!(
+                "Unknown search mode {}, using 'keyword'. Allowed modes are: keyword, category",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("ANSWER_FOOTER") {
+        match val.as_ref() {
+            "" | "0" | "false" => c.answer_footer = None,
+            "1" | "true" => c.answer_footer = Some(DEFAULT_FOOTER.into()),
+            _ => c.answer_footer = Some(val),
+        }
+    }
+    if let Ok(val) = var("INCLUDE_LINKED") {
+        match val.parse::<u32>() {
+            Ok(n) => c.include_linked = n,
+            Err(_) => eprintln!("Ignoring invalid INCLUDE_LINKED {}", val),
+        }
+    }
+    if let Ok(val) = var("ANSWER_MAX_WORDS") {
+        match val.parse::<usize>() {
+            Ok(n) if n > 0 => c.answer_max_words = Some(n),
+            _ => eprintln!("Ignoring invalid ANSWER_MAX_WORDS {}", val),
+        }
+    }
+    if let Ok(val) = var("COMPACT_CONTEXT") {
+        match val.parse::<usize>() {
+            Ok(0) => c.compact_context = None,
+            Ok(n) => c.compact_context = Some(n),
+            _ => eprintln!("Ignoring invalid COMPACT_CONTEXT {}", val),
+        }
+    }
+    if let Ok(val) = var("SYNONYMS_FILE") {
+        if !val.is_empty() {
+            match load_synonyms(&val) {
+                Ok(synonyms) => c.synonyms = synonyms,
+                Err(e) => eprintln!("Ignoring synonyms file {}: {}", val, e),
+            }
+        }
+    }
+    if let Ok(val) = var("READING_LEVEL") {
+        match val.as_ref() {
+            "" | "general" => c.reading_level = ReadingLevel::General,
+            "child" => c.reading_level = ReadingLevel::Child,
+            "teen" => c.reading_level = ReadingLevel::Teen,
+            "expert" => c.reading_level = ReadingLevel::Expert,
+            _ => eprintln!(
+                "Unknown reading level {}, using 'general'. Allowed levels are: child, teen, general, expert",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("PREFER_QUALITY") {
+        c.prefer_quality = env_flag(&val);
+    }
+    if let Ok(val) = var("CONTEXT_WINDOW_CHARS") {
+        match val.parse::<usize>() {
+            Ok(n) if n > 0 => c.context_window_chars = Some(n),
+            _ => eprintln!("Ignoring invalid CONTEXT_WINDOW_CHARS {}", val),
+        }
+    }
+    if let Ok(val) = var("MAX_LLM_CALLS") {
+        match val.parse::<u32>() {
+            Ok(n) if n > 0 => c.max_llm_calls = Some(n),
+            _ => eprintln!("Ignoring invalid MAX_LLM_CALLS {}", val),
+        }
+    }
+    if let Ok(val) = var("SOURCE_TRUST") {
+        c.source_trust = parse_source_trust(&val);
+    }
+    if let Ok(val) = var("TABLE_COLUMNS") {
+        c.table_columns = parse_table_columns(&val);
+    }
+    if let Ok(val) = var("BEST_PAGE_ONLY") {
+        c.best_page_only = env_flag(&val);
+    }
+    if let Ok(val) = var("KEYWORD_METHOD") {
+        match val.as_ref() {
+            "" | "llm" => c.keyword_method = KeywordMethod::Llm,
+            "local" => c.keyword_method = KeywordMethod::Local,
+            _ => eprintln!(
+                "Unknown keyword method {}, using 'llm'. Allowed methods are: llm, local",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("STOPWORDS_FILE") {
+        if !val.is_empty() {
+            match stopwords::load(&val) {
+                Ok(words) => c.stopwords = Some(words),
+                Err(e) => eprintln!("Ignoring stopwords file {}: {}", val, e),
+            }
+        }
+    }
+    if let Ok(val) = var("STREAM") {
+        c.stream = env_flag(&val);
+    }
+    if let Ok(val) = var("STREAM_RETRIES") {
+        match val.parse::<u32>() {
+            Ok(n) => c.stream_retries = n,
+            Err(_) => eprintln!("Ignoring invalid STREAM_RETRIES {}", val),
+        }
+    }
+    if let Ok(val) = var("REWRITE_QUESTION") {
+        c.rewrite_question = env_flag(&val);
+    }
+    if let Ok(val) = var("ADAPTIVE_CONTEXT") {
+        c.adaptive_context = env_flag(&val);
+    }
+    if let Ok(val) = var("WITH_CONFIDENCE") {
+        c.with_confidence = env_flag(&val);
+    }
+    if let Ok(val) = var("DECOMPOSE") {
+        c.decompose = env_flag(&val);
+    }
+    if let Ok(val) = var("CITATION_STYLE") {
+        match val.as_ref() {
+            "" | "plain" => c.citation_style = CitationStyle::Plain,
+            "bibtex" => c.citation_style = CitationStyle::Bibtex,
+            "apa" => c.citation_style = CitationStyle::Apa,
+            _ => eprintln!(
+                "Unknown citation style {}, using 'plain'. Allowed styles are: plain, bibtex, apa",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("RERANK") {
+        c.rerank = env_flag(&val);
+    }
+    if let Ok(val) = var("ENTITY_BOOST") {
+        c.entity_boost = env_flag(&val);
+    }
+    if let Ok(val) = var("RERANK_MIN_SIMILARITY") {
+        match val.parse::<f64>() {
+            Ok(min) if (-1.0..=1.0).contains(&min) => c.rerank_min_similarity = Some(min),
+            _ => eprintln!("Ignoring invalid RERANK_MIN_SIMILARITY {}", val),
+        }
+    }
+    if let Ok(val) = var("EMBEDDING_MODEL") {
+        if !val.is_empty() {
+            c.embedding_model = Some(val);
+        }
+    }
+    if let Ok(val) = var("EMBEDDING_PROVIDER") {
+        match val.to_lowercase().as_str() {
+            "" => {}
+            "openai" => c.embedding_provider = Some(LlmProvider::OpenAI),
+            "ollama" => c.embedding_provider = Some(LlmProvider::Ollama),
+            _ => eprintln!(
+                "Ignoring invalid EMBEDDING_PROVIDER {}, allowed are openai and ollama",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("ANSWER_SCHEMA") {
+        if !val.is_empty() {
+            match load_answer_schema(&val) {
+                Ok(schema) => {
+                    c.answer_schema = Some(schema);
+                    c.answer_schema_error = None;
+                }
+                Err(e) => {
+                    c.answer_schema = None;
+                    c.answer_schema_error = Some(format!("invalid ANSWER_SCHEMA {}: {}", val, e));
+                }
+            }
+        }
+    }
+    if let Ok(val) = var("AUDIT_DIR") {
+        c.audit_dir = Some(val).filter(|d| !d.is_empty());
+    }
+    if let Ok(val) = var("DRY_RUN") {
+        c.dry_run = env_flag(&val);
+    }
+    if let Ok(val) = var("USAGE_LOG") {
+        c.usage_log = Some(val).filter(|f| !f.is_empty());
+    }
+    if let Ok(val) = var("OUTPUT_FORMAT") {
+        if !val.is_empty() {
+            c.format = parse_output_format(&val);
+        }
+    }
+    if let Ok(val) = var("OUTPUT_FILE") {
+        if !val.is_empty() {
+            c.output_file = Some(val);
+        }
+    }
+    if let Ok(val) = var("KEYWORD_PROMPT") {
+        c.keyword_prompt = Some(val).filter(|t| !t.trim().is_empty());
+    }
+    if let Ok(val) = var("ANSWER_PROMPT") {
+        c.answer_prompt = Some(val).filter(|t| !t.trim().is_empty());
+    }
+    if let Ok(val) = var("INTRO_ONLY") {
+        c.intro_only = env_flag(&val);
+    }
+    if let Ok(val) = var("WIKI_SOURCE") {
+        match val.strip_prefix("local:") {
+            Some(dir) if std::path::Path::new(dir).is_dir() => {
+                c.local_source = Some(dir.into());
+            }
+            Some(dir) => eprintln!("Ignoring WIKI_SOURCE, {} is not a directory", dir),
+            None if val.is_empty() || val == "api" => c.local_source = None,
+            None => eprintln!(
+                "Ignoring invalid WIKI_SOURCE {}, use 'api' or 'local:/path/to/dir'",
+                val
+            ),
+        }
+    }
+    if let Ok(val) = var("WIKI_API_URL") {
+        c.wiki_api = Some(val).filter(|u| !u.is_empty());
+    }
+    let mut http_changed = false;
+    if let Ok(val) = var("HTTP_TIMEOUT") {
+        match val.parse::<u64>() {
+            Ok(secs) if secs > 0 => {
+                c.http_timeout = Duration::from_secs(secs);
+                http_changed = true;
+            }
+            _ => eprintln!("Ignoring invalid HTTP_TIMEOUT {}", val),
+        }
+    }
+    if let Ok(val) = var("WIKI_USER_AGENT") {
+        match reqwest::header::HeaderValue::from_str(val.trim()) {
+            Ok(_) if !val.trim().is_empty() => {
+                c.user_agent = val.trim().to_string();
+                http_changed = true;
+            }
+            _ => eprintln!("Ignoring invalid WIKI_USER_AGENT {:?}", val),
+        }
+    }
+    if http_changed {
+        c.http = http_client(c.http_timeout, &c.user_agent);
+    }
+    // Account limits of the OpenAI API, unset means unlimited:
+    let rpm = var("OPENAI_RPM").ok().and_then(|v| v.parse::<u32>().ok());
+    let tpm = var("OPENAI_TPM").ok().and_then(|v| v.parse::<u32>().ok());
+    if rpm.is_some() || tpm.is_some() {
+        c.rate_limiter = RateLimiter::new(rpm, tpm);
+    }
+    if let Ok(val) = var("SKIP_TITLE_PATTERNS") {
+        c.skip_title_patterns = parse_title_patterns(&val);
+    }
+}
+
+/// Reads a JSON schema file and checks that it is a valid schema.
+fn load_answer_schema(path: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let schema: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    jsonschema::validator_for(&schema).map_err(|e| e.to_string())?;
+    Ok(schema)
+}
+
+fn warn_unknown_context_window(c: &Config) {
+    if c.context_tokens.is_none() && model_context_window(&c.model).is_none() {
+        eprintln!(
+            "Context window of model {} is unknown, assuming {} tokens. Set MODEL_CONTEXT_TOKENS to override.",
+            c.model, DEFAULT_CONTEXT_TOKENS
+        );
+    }
+}
+
+/// Applies a configuration given as a JSON object, for example
+/// `{"AI_MODEL": "gpt-4o", "wiki_pages": 3}`. Keys are the names of the
+/// environment variables, in any case; numbers and booleans are accepted
+/// as well as strings, lists for the comma separated settings. Unknown
+/// keys are reported.
+pub fn apply_config_json(c: &mut Config, json: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)?;
+    let mut settings: HashMap<String, String> = HashMap::new();
+    for (key, value) in object.iter() {
+        let text = match value {
+            serde_json::Value::Null => continue,
+            serde_json::Value::Bool(b) => if *b { "1" } else { "" }.to_string(),
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|i| match i {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        };
+        settings.insert(key.to_uppercase(), text);
+    }
+
+    let used = std::cell::RefCell::new(vec![]);
+    apply_settings(c, &|name| {
+        used.borrow_mut().push(name.to_string());
+        settings
+            .get(name)
+            .cloned()
+            .ok_or(std::env::VarError::NotPresent)
+    });
+    let used = used.into_inner();
+    for key in object.keys() {
+        if !used.contains(&key.to_uppercase()) {
+            eprintln!("Warning: ignoring unknown configuration field '{}'", key);
+        }
+    }
+    if settings.contains_key("AI_MODEL") || settings.contains_key("MODEL_CONTEXT_TOKENS") {
+        warn_unknown_context_window(c);
+    }
+    Ok(())
+}
+
+/// Reads a JSON object mapping a phrase to a list of synonyms, for
+/// example `{"heart attack": ["myocardial infarction"]}`.
+fn load_synonyms(path: &str) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error>> {
+    let map: HashMap<String, Vec<String>> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(map
+        .into_iter()
+        .map(|(k, v)| (k.trim().to_lowercase(), v))
+        .filter(|(k, _)| !k.is_empty())
+        .collect())
+}
+
+/// Parses a comma-separated list of `pattern=weight` entries, where the
+/// pattern is a regex matched against the source titles.
+fn parse_source_trust(val: &str) -> Vec<(Regex, u32)> {
+    let mut trust = vec![];
+    for entry in val.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let parsed = entry
+            .rsplit_once('=')
+            .and_then(|(p, w)| Some((Regex::new(p.trim()).ok()?, w.trim().parse::<u32>().ok()?)));
+        match parsed {
+            Some(t) => trust.push(t),
+            None => eprintln!(
+                "Ignoring invalid SOURCE_TRUST entry '{}', expected <pattern>=<weight>.",
+                entry
+            ),
+        }
+    }
+    trust
+}
+
+/// Parses a comma-separated list of title patterns. Every entry is taken
+/// as a regular expression; entries which are not valid regexes are
+/// matched as plain substrings instead.
+fn parse_title_patterns(val: &str) -> Vec<Regex> {
+    val.split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| match Regex::new(p) {
+            Ok(re) => re,
+            Err(_) => {
+                eprintln!(
+                    "Title pattern '{}' is not a valid regex, matching it literally.",
+                    p
+                );
+                Regex::new(&regex::escape(p)).unwrap()
+            }
+        })
+        .collect()
+}
+
+/// Validates a Wikipedia language code like "de", falling back to
+/// English for anything which is not 2 or 3 letters.
+fn parse_language(val: &str) -> String {
+    let code = val.trim().to_lowercase();
+    if (2..=3).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        code
+    } else {
+        eprintln!(
+            "Invalid Wikipedia language '{}', it must be a code of 2 or 3 letters like 'de'. Using 'en'.",
+            val
+        );
+        "en".into()
+    }
+}
+
+fn parse_output_format(val: &str) -> OutputFormat {
+    match val {
+        "text" => OutputFormat::Text,
+        "markdown" => OutputFormat::Markdown,
+        "json" => OutputFormat::Json,
+        _ => {
+            eprintln!(
+                "Unknown output format {} requested, falling back to 'text'.
+Allowed formats are: text, markdown, json
+",
+                val
+            );
+            OutputFormat::Text
+        }
+    }
+}
+
+/// Command line options. The settings which can also be given in the
+/// environment show their variable in `--help`; values from the
+/// environment are applied with all other settings by
+/// [`get_config_from_env`], options given here override them.
+#[derive(Parser)]
+#[command(version, about = "Answers questions with an LLM and Wikipedia")]
+struct Cli {
+    /// The question to answer. Without it, the question is read from stdin.
+    question: Option<String>,
+    /// Read the question from this file.
+    #[arg(long, value_name = "PATH", conflicts_with = "question")]
+    question_file: Option<String>,
+    /// LLM model to use.
+    #[arg(long, env = "AI_MODEL")]
+    model: Option<String>,
+    /// Show details of every step on stderr.
+    #[arg(long, env = "VERBOSE", value_parser = FalseyValueParser::new())]
+    verbose: bool,
+    /// Show nothing but the answer and errors.
+    #[arg(long, env = "QUIET", value_parser = FalseyValueParser::new())]
+    quiet: bool,
+    /// Number of Wikipedia pages to give to the LLM.
+    #[arg(long, env = "WIKI_PAGES")]
+    wiki_pages: Option<u32>,
+    /// Maximal length of the answer in tokens.
+    #[arg(long, env = "ANSWER_MAX_TOKENS")]
+    max_tokens: Option<u32>,
+    /// Wikipedia language to search, like "de".
+    #[arg(long)]
+    lang: Option<String>,
+    /// Keep asking follow-up questions about the retrieved pages after
+    /// the first answer, '/new' searches again.
+    #[arg(long)]
+    interactive: bool,
+    /// Show the passages supporting the claims of the answer.
+    #[arg(long)]
+    explain: bool,
+    /// Output format: text, markdown or json.
+    #[arg(long, alias = "output")]
+    format: Option<String>,
+    /// Also write the answer to this file.
+    #[arg(long)]
+    output_file: Option<String>,
+    /// Save the conversation to this file and resume it from there.
+    #[arg(long)]
+    session: Option<String>,
+    /// Summarize every page to about this many characters before
+    /// answering.
+    #[arg(long, value_name = "CHARS")]
+    compact_context: Option<usize>,
+    /// Download every page, even when it is in the cache.
+    #[arg(long)]
+    no_cache: bool,
+    /// Derive keywords and search as usual, but show the answer request
+    /// with its size instead of sending it.
+    #[arg(long)]
+    dry_run: bool,
+    /// Use only the lead section of every page, for overview questions.
+    #[arg(long)]
+    intro_only: bool,
+    /// System prompt for deriving the keywords, instead of the built-in
+    /// one. {question} is replaced with the question.
+    #[arg(long, env = "KEYWORD_PROMPT", value_name = "TEMPLATE")]
+    keyword_prompt: Option<String>,
+    /// Instruction for answering, instead of the built-in one. {question}
+    /// is replaced with the question, which otherwise follows the
+    /// instruction, and {pages} with the page texts, which are otherwise
+    /// given separately.
+    #[arg(long, env = "ANSWER_PROMPT", value_name = "TEMPLATE")]
+    answer_prompt: Option<String>,
+    /// Settings as a JSON object with the environment variable names as
+    /// keys. They override the environment, but not the other options.
+    #[arg(long)]
+    config_json: Option<String>,
+}
+
+/// Applies the command line options to the configuration and returns the
+/// question given on the command line, if any. A request for the help or
+/// the version comes back as a [`WikiRagError::Usage`] too.
+pub fn apply_args(c: &mut Config) -> Result<Option<String>, WikiRagError> {
+    let matches = Cli::command()
+        .try_get_matches()
+        .map_err(WikiRagError::Usage)?;
+    let cli = Cli::from_arg_matches(&matches).map_err(WikiRagError::Usage)?;
+    if let Some(json) = &cli.config_json {
+        apply_config_json(c, json)
+            .map_err(|e| WikiRagError::Config(format!("invalid --config-json: {}", e)))?;
+    }
+
+    // Values clap took from the environment are applied already, and must
+    // not override the JSON configuration:
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut settings: HashMap<&str, String> = HashMap::new();
+    if let Some(model) = cli.model.filter(|_| given("model")) {
+        settings.insert("AI_MODEL", model);
+    }
+    if cli.verbose && given("verbose") {
+        settings.insert("VERBOSE", "1".into());
+    }
+    if cli.quiet && given("quiet") {
+        settings.insert("QUIET", "1".into());
+    }
+    if let Some(n) = cli.wiki_pages.filter(|_| given("wiki_pages")) {
+        settings.insert("WIKI_PAGES", n.to_string());
+    }
+    if let Some(n) = cli.max_tokens.filter(|_| given("max_tokens")) {
+        settings.insert("ANSWER_MAX_TOKENS", n.to_string());
+    }
+    if let Some(t) = cli.keyword_prompt.filter(|_| given("keyword_prompt")) {
+        settings.insert("KEYWORD_PROMPT", t);
+    }
+    if let Some(t) = cli.answer_prompt.filter(|_| given("answer_prompt")) {
+        settings.insert("ANSWER_PROMPT", t);
+    }
+    apply_settings(c, &|name| {
+        settings
+            .get(name)
+            .cloned()
+            .ok_or(std::env::VarError::NotPresent)
+    });
+
+    if let Some(lang) = cli.lang {
+        c.language = parse_language(&lang);
+    }
+    if cli.interactive {
+        c.interactive = true;
+    }
+    if cli.explain {
+        c.explain = true;
+    }
+    if let Some(format) = cli.format {
+        c.format = parse_output_format(&format);
+    }
+    if cli.output_file.is_some() {
+        c.output_file = cli.output_file;
+    }
+    if cli.session.is_some() {
+        c.session_file = cli.session;
+    }
+    if cli.no_cache {
+        c.page_cache = false;
+    }
+    if cli.dry_run {
+        c.dry_run = true;
+    }
+    if cli.intro_only {
+        c.intro_only = true;
+    }
+    if let Some(n) = cli.compact_context {
+        c.compact_context = Some(n).filter(|n| *n > 0);
+    }
+    if let Some(path) = &cli.question_file {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            WikiRagError::Config(format!("cannot read the question from {}: {}", path, e))
+        })?;
+        return Ok(Some(text.trim().to_string()));
+    }
+    Ok(cli.question)
+}
+
+pub fn greet() {
+    eprintln!(
+        "This is WikiRag!
+
+I will answer your question using knowledge from Wikipedia. I will first
+use a LLM to derive key words to perform a search in Wikipedia and will
+then retrieve the relevant pages. I will then feed these pages to the
+LLM and let it answer your questions in this way. In the end you get the
+answer plus a citation into Wikipedia.
+"
+    );
+}
+
+pub fn wiki_url(language: &str, title: &str) -> String {
+    format!(
+        "https://{}.wikipedia.org/wiki/{}",
+        language,
+        title.replace(" ", "_")
+    )
+}
+
+/// A link to a page by its id, which keeps working when the page is
+/// renamed.
+pub fn curid_url(language: &str, page_id: &str) -> String {
+    format!("https://{}.wikipedia.org/?curid={}", language, page_id)
+}
+
+/// Context window used for models we know nothing about.
+const DEFAULT_CONTEXT_TOKENS: u32 = 4096;
+
+/// Maximal number of tokens in an answer, unless set with
+/// `ANSWER_MAX_TOKENS`.
+const DEFAULT_ANSWER_MAX_TOKENS: u32 = 1000;
+
+/// Fewer tokens than this hardly make a sentence.
+const MIN_ANSWER_TOKENS: u32 = 16;
+
+/// The context window (prompt plus completion) of known models in tokens.
+pub fn model_context_window(model: &str) -> Option<u32> {
+    match model {
+        "gpt-3.5-turbo" => Some(16_385),
+        "gpt-4" => Some(8_192),
+        "gpt-4-turbo" | "gpt-4o" | "gpt-4o-mini" => Some(128_000),
+        "llama3" => Some(8_192),
+        "claude-3-5-sonnet" | "claude-3-haiku" => Some(200_000),
+        _ => None,
+    }
+}
+
+/// The context window to budget with: the configured override, the known
+/// size of the model, or a conservative default.
+fn context_window(config: &Config) -> u32 {
+    config
+        .context_tokens
+        .or_else(|| model_context_window(&config.model))
+        .unwrap_or(DEFAULT_CONTEXT_TOKENS)
+}
+
+/// Checks that an answer of `ANSWER_MAX_TOKENS` leaves room for the
+/// prompt in the context window of the model.
+pub fn validate_answer_max_tokens(config: &Config) -> Result<(), WikiRagError> {
+    let window = context_window(config);
+    if config.answer_max_tokens + PROMPT_RESERVE_TOKENS > window {
+        return Err(WikiRagError::Config(format!(
+            "ANSWER_MAX_TOKENS={} leaves no room for the prompt in the {} tokens of model {}, use at most {}.",
+            config.answer_max_tokens,
+            window,
+            config.model,
+            window.saturating_sub(PROMPT_RESERVE_TOKENS)
+        )));
+    }
+    Ok(())
+}
+
+/// Fails if the `ANSWER_SCHEMA` could not be loaded, since scripts relying
+/// on it cannot use a prose answer instead.
+pub fn validate_answer_schema(config: &Config) -> Result<(), WikiRagError> {
+    match &config.answer_schema_error {
+        Some(e) => Err(WikiRagError::Config(e.clone())),
+        None => Ok(()),
+    }
+}
+
+/// The exchanges of a conversation which are still sent to the model.
+/// Requests to Ollama keep the system message with the pages, and leave
+/// out the oldest answers and questions beyond `OLLAMA_HISTORY_SIZE`.
+fn retained_history<'a>(config: &Config, history: &'a [Exchange]) -> &'a [Exchange] {
+    if config.llm_server != LlmProvider::Ollama {
+        return history;
+    }
+    // The system message and the reply to the new question take two
+    // places, every earlier exchange two more:
+    let keep = (config.ollama_history_size as usize).saturating_sub(2) / 2;
+    &history[history.len().saturating_sub(keep)..]
+}
+
+/// About how many tokens the pages, the question and the retained
+/// history take in the answer prompt.
+fn estimate_prompt_tokens(
+    config: &Config,
+    pages: &[String],
+    history: &[Exchange],
+    question: &str,
+) -> u32 {
+    let mut tokens = ratelimit::estimate_tokens(question);
+    for p in pages {
+        tokens += ratelimit::estimate_tokens(p);
+    }
+    for e in retained_history(config, history) {
+        tokens += ratelimit::estimate_tokens(&e.question) + ratelimit::estimate_tokens(&e.answer);
+    }
+    tokens
+}
+
+/// Fails if the answer prompt would not fit into the context window of
+/// the model, instead of letting the provider reject it. Warns when an
+/// Ollama conversation comes close to it.
+fn check_prompt_fits(
+    config: &Config,
+    pages: &[String],
+    history: &[Exchange],
+    question: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tokens =
+        estimate_prompt_tokens(config, pages, history, question) + config.answer_max_tokens;
+    let retained = retained_history(config, history);
+    let window = context_window(config);
+    if config.llm_server == LlmProvider::Ollama && !retained.is_empty() && tokens > window / 4 * 3 {
+        progress!(
+            config,
+            "Warning: the page extracts and {} earlier questions take about {} of the {} tokens of model {}. Lower OLLAMA_HISTORY_SIZE or WIKI_PAGES if answers lose track.",
+            retained.len(),
+            tokens,
+            window,
+            config.model
+        );
+    }
+    if tokens > window {
+        return Err(format!(
+            "the prompt needs about {} tokens, but model {} only has a context window of {} tokens. Use fewer pages (WIKI_PAGES) or set MODEL_CONTEXT_TOKENS if the model supports more.",
+            tokens, config.model, window
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Dollars per million prompt and completion tokens of the models with
+/// known prices.
+fn model_prices(model: &str) -> Option<(f64, f64)> {
+    match model {
+        "gpt-4-turbo" => Some((10.0, 30.0)),
+        "gpt-3.5-turbo" => Some((0.5, 1.5)),
+        "gpt-4o" => Some((5.0, 15.0)),
+        "gpt-4o-mini" => Some((0.15, 0.6)),
+        "claude-3-5-sonnet" => Some((3.0, 15.0)),
+        "claude-3-haiku" => Some((0.25, 1.25)),
+        _ => None,
+    }
+}
+
+/// The costs in dollars of the prompt and completion tokens with a model,
+/// zero for models run locally or with unknown prices.
+pub fn token_costs(model: &str, prompt_tokens: u64, completion_tokens: u64) -> (f64, f64) {
+    let (in_price, out_price) = model_prices(model).unwrap_or((0.0, 0.0));
+    (
+        prompt_tokens as f64 / 1_000_000.0 * in_price,
+        completion_tokens as f64 / 1_000_000.0 * out_price,
+    )
+}
+
+#[derive(Default)]
+struct UsageTotals {
+    calls: u32,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+}
+
+/// Tokens used by all LLM calls made with a configuration, and what they
+/// cost.
+#[derive(Default)]
+pub struct TokenUsage {
+    totals: Mutex<UsageTotals>,
+}
+
+impl TokenUsage {
+    fn add(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        let (in_costs, out_costs) = token_costs(model, prompt_tokens, completion_tokens);
+        let mut totals = self.totals.lock().unwrap();
+        totals.prompt_tokens += prompt_tokens;
+        totals.completion_tokens += completion_tokens;
+        totals.cost += in_costs + out_costs;
+        in_costs + out_costs
+    }
+
+    /// Counts another LLM call, returns the number of calls so far.
+    fn count_call(&self) -> u32 {
+        let mut totals = self.totals.lock().unwrap();
+        totals.calls += 1;
+        totals.calls
+    }
+
+    pub fn calls(&self) -> u32 {
+        self.totals.lock().unwrap().calls
+    }
+
+    pub fn prompt_tokens(&self) -> u64 {
+        self.totals.lock().unwrap().prompt_tokens
+    }
+
+    pub fn completion_tokens(&self) -> u64 {
+        self.totals.lock().unwrap().completion_tokens
+    }
+
+    /// The cost in dollars.
+    pub fn cost(&self) -> f64 {
+        self.totals.lock().unwrap().cost
+    }
+}
+
+/// Adds the tokens of an LLM call to the totals of the run and to the
+/// `USAGE_LOG`.
+fn record_usage(config: &Config, prompt_tokens: u64, completion_tokens: u64) {
+    let cost = config
+        .usage
+        .add(&config.model, prompt_tokens, completion_tokens);
+    usage_log::append(config, prompt_tokens, completion_tokens, cost);
+}
+
+/// Shows the tokens and cost of all LLM calls of the run.
+pub fn print_session_total(config: &Config) {
+    progress!(
+        config,
+        "Session total: {} LLM calls, tokens in: {}, tokens out: {}, cost: ${:.6}",
+        config.usage.calls(),
+        config.usage.prompt_tokens(),
+        config.usage.completion_tokens(),
+        config.usage.cost()
+    );
+}
+
+/// Counts an LLM call, and fails before the call is made if it would
+/// exceed `MAX_LLM_CALLS`.
+fn count_llm_call(config: &Config) -> Result<(), WikiRagError> {
+    let calls = config.usage.count_call();
+    match config.max_llm_calls {
+        Some(max) if calls > max => Err(WikiRagError::LlmCallLimit(max)),
+        _ => Ok(()),
+    }
+}
+
+fn pretty_print_usage(config: &Config, usage: Option<CompletionUsage>) {
+    if let Some(usage) = usage {
+        print_token_usage(
+            config,
+            usage.prompt_tokens as u64,
+            usage.completion_tokens as u64,
+        );
+    }
+}
+
+/// Adds the tokens of a call to the usage and shows what they cost.
+fn print_token_usage(config: &Config, prompt_tokens: u64, completion_tokens: u64) {
+    if config.llm_server != LlmProvider::Ollama && model_prices(&config.model).is_none() {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| {
+            eprintln!(
+                "Warning: prices of model {} are unknown, its costs are counted as zero.",
+                config.model
+            )
+        });
+    }
+    record_usage(config, prompt_tokens, completion_tokens);
+    let (in_costs, out_costs) = token_costs(&config.model, prompt_tokens, completion_tokens);
+    progress!(
+        config,
+        "Tokens in: {} (${:.6}), tokens out: {} (${:.6})",
+        prompt_tokens,
+        in_costs,
+        completion_tokens,
+        out_costs
+    );
+}
+
+/// Sends a GET request to the Wikipedia API and returns the response
+/// body. Transient failures, timeouts among them, are retried.
+async fn wiki_get(
+    config: &Config,
+    url: &str,
+    params: &[(&str, &str)],
+) -> Result<String, Box<dyn std::error::Error>> {
+    if config.local_source.is_some() {
+        return Err("the Wikipedia API is not used with a local WIKI_SOURCE".into());
+    }
+    let client = &config.http;
+    retry::retry(config, "Wikipedia request", move || async move {
+        let response = client.get(url).query(params).send().await?;
+        let body = response.error_for_status()?.text().await?;
+        match retry::wiki_api_error(&body) {
+            Some(e) => Err(e.into()),
+            None => Ok(body),
+        }
+    })
+    .await
+}
+
+fn wiki_api_url(config: &Config) -> String {
+    match &config.wiki_api {
+        Some(url) => url.clone(),
+        None => format!("https://{}.wikipedia.org/w/api.php", config.language),
+    }
+}
+
+/// The system prompt for keyword extraction. If the question does not seem
+/// to be written in the language of the Wikipedia we search, the model is
+/// asked to translate the keyword, since e.g. an English keyword will not
+/// match the title of a German article.
+fn keyword_prompt(config: &Config, question: &str) -> String {
+    if let Some(template) = &config.keyword_prompt {
+        return fill_template(template, question, "");
+    }
+    let mut prompt = match (&config.search_mode, config.keywords) {
+        (SearchMode::Keyword, 1) => "Extract exactly one keyword from the user's question for a Wikipedia lookup, respond with just the single keyword.".to_string(),
+        (SearchMode::Keyword, n) => format!("Extract up to {} keywords from the user's question for a Wikipedia lookup, one for each distinct topic of the question, most important first. Respond with just the keywords on one line, separated by semicolons.", n),
+        (SearchMode::Category, 1) => "Name exactly one Wikipedia category whose member pages are most relevant for the user's question, respond with just the category name without the 'Category:' prefix.".to_string(),
+        (SearchMode::Category, n) => format!("Name up to {} Wikipedia categories whose member pages are most relevant for the user's question, one for each distinct topic of the question, most important first. Respond with just the category names without the 'Category:' prefix on one line, separated by semicolons.", n),
+    };
+    let detected = lang::detect_language(question);
+    if detected != Some(config.language.as_str()) {
+        if config.verbose {
+            eprintln!(
+                "Question language detected as {}, searching the '{}' Wikipedia.",
+                detected.unwrap_or("unknown"),
+                config.language
+            );
+        }
+        prompt.push_str(&format!(
+            " The keyword is used to search the {} Wikipedia, so give it in {}, translating it if necessary.",
+            lang::language_name(&config.language),
+            lang::language_name(&config.language)
+        ));
+    }
+    if config.keyword_entity_hint {
+        prompt.push_str(
+            " First identify the central named entity (person, place, event, work or concept) the question is about, and use it as the keyword if there is one.",
+        );
+    }
+    if config.keyword_alternatives > 0 {
+        prompt.push_str(&format!(
+            " Then, each on its own line, give up to {} alternative keywords to try if the first one finds nothing, most promising first.",
+            config.keyword_alternatives
+        ));
+    }
+    prompt
+}
+
+/// Maximal number of tokens for the keyword response.
+fn keyword_max_tokens(config: &Config) -> u32 {
+    let base = 32 * (config.keywords + config.keyword_alternatives);
+    learned_keyword_tokens(&config.model).map_or(base, |learned| learned.max(base))
+}
+
+/// Where the keyword token limits learned per model are kept, so that
+/// later runs start with a limit the model does not overrun.
+fn keyword_tokens_file() -> Option<std::path::PathBuf> {
+    Some(cache::cache_dir()?.join("keyword_tokens.json"))
+}
+
+fn read_keyword_tokens() -> HashMap<String, u32> {
+    keyword_tokens_file()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn learned_keyword_tokens(model: &str) -> Option<u32> {
+    read_keyword_tokens().get(model).copied()
+}
+
+/// Remembers the token limit for a model. Failing to write the cache
+/// only costs the retry next time, so errors are ignored.
+fn learn_keyword_tokens(model: &str, tokens: u32) {
+    let Some(path) = keyword_tokens_file() else {
+        return;
+    };
+    let mut learned = read_keyword_tokens();
+    learned.insert(model.to_string(), tokens);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Ok(text) = serde_json::to_string_pretty(&learned) {
+        let _ = std::fs::write(path, text);
+    }
+}
+
+/// A lead-in models put before the keyword, like "The keyword is:" or
+/// "Sure, here are the search terms -".
+static KEYWORD_LEAD_IN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^(?:(?:sure|okay|ok)[,!.]?\s*)?(?:here\s+(?:is|are)\s+)?(?:the\s+|a\s+|my\s+)?(?:(?:best|single|wikipedia|search)\s+)*(?:keywords?|search\s+terms?|categor(?:y|ies))(?:\s+for\s+(?:the\s+)?(?:lookup|search|wikipedia|this\s+question))?\s*(?::|\s-\s|\s+(?:is|are|would\s+be)\b:?)\s*",
+    )
+    .unwrap()
+});
+
+/// A keyword ending in an abbreviation like "D.C.", whose period stays.
+static ABBREVIATION_END: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[\s.])\p{Lu}\.$").unwrap());
+
+/// A list marker like "1." or "-" before a keyword.
+static LIST_MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*(?:[-*]|\d+[.)])\s+").unwrap());
+
+/// Cleans a keyword as models give it: without a lead-in like "The
+/// keyword is:", surrounding quotes or emphasis, and trailing punctuation.
+/// The period of an abbreviation like "D.C." is kept.
+fn sanitize_keyword(raw: &str) -> String {
+    let mut keyword = raw.trim().to_string();
+    loop {
+        let before = keyword.clone();
+        keyword = KEYWORD_LEAD_IN.replace(&keyword, "").trim().to_string();
+        keyword = keyword
+            .trim_matches(|c: char| "\"'`*_“”‘’«»".contains(c))
+            .trim()
+            .to_string();
+        while keyword.ends_with([',', ';', ':', '!', '?'])
+            || (keyword.ends_with('.') && !ABBREVIATION_END.is_match(&keyword))
+        {
+            keyword.pop();
+        }
+        if keyword == before {
+            return keyword;
+        }
+    }
+}
+
+/// Asks the LLM for a broader search term than the ones which found
+/// nothing.
+async fn broader_keyword(
+    config: &Config,
+    question: &str,
+    tried: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let system = format!(
+        "A Wikipedia search for the user's question found no usable pages with the search terms '{}'. Give one broader or more common search term which likely finds a relevant article, respond with just the term.",
+        tried.join("', '")
+    );
+    let response = complete(config, &system, question, keyword_max_tokens(config)).await?;
+    let last = response.lines().rfind(|l| !l.trim().is_empty());
+    Ok(sanitize_keyword(last.unwrap_or("")))
+}
+
+/// Turns the keyword response of the LLM (the keywords to search
+/// together on the first line, then one alternative per line) plus the
+/// configured fallback keywords into the ordered list of searches to try.
+fn keyword_chain(config: &Config, response: &str) -> Vec<Vec<String>> {
+    let mut chain: Vec<Vec<String>> = vec![];
+    // Strip list markers like "1." or "-" which models like to add, and
+    // lines like "Here is what I found:" introducing the keywords:
+    let mut lines: Vec<String> = response
+        .lines()
+        .filter(|l| !l.trim_end().ends_with(':'))
+        .map(|l| sanitize_keyword(&LIST_MARKER.replace(l, "")))
+        .filter(|l| !l.is_empty())
+        .collect();
+    // Without alternatives asked for, more lines are the model thinking
+    // aloud, and the keyword comes last:
+    if config.keyword_alternatives == 0 && lines.len() > 1 {
+        lines.drain(..lines.len() - 1);
+    }
+    let mut lines = lines.into_iter();
+    // The first line holds the keywords searched together:
+    if let Some(first) = lines.next() {
+        let together: Vec<String> = if config.keywords > 1 {
+            first
+                .split(';')
+                .map(sanitize_keyword)
+                .filter(|k| !k.is_empty())
+                .take(config.keywords as usize)
+                .collect()
+        } else {
+            vec![first]
+        };
+        if !together.is_empty() {
+            chain.push(together);
+        }
+    }
+    for k in lines.chain(config.fallback_keywords.iter().cloned()) {
+        if !k.is_empty() && !chain.iter().flatten().any(|c| *c == k) {
+            chain.push(vec![k]);
+        }
+    }
+    chain
+}
+
+/// Searches for one keyword and its synonyms, in the configured search
+/// mode.
+async fn search_keyword(
+    config: &Config,
+    keyword: &str,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let mut pages = match config.search_mode {
+        SearchMode::Keyword => search_wikipedia(config, keyword).await?,
+        SearchMode::Category => search_category_or_keyword(config, keyword).await?,
+    };
+    for e in expand_synonyms(config, keyword) {
+        progress!(
+            config,
+            "Also searching for synonym '{}' of '{}'...",
+            e,
+            keyword
+        );
+        let more = match config.search_mode {
+            SearchMode::Keyword => search_wikipedia(config, &e).await?,
+            SearchMode::Category => search_category_or_keyword(config, &e).await?,
+        };
+        for p in more {
+            if !pages.iter().any(|q| q.page_id == p.page_id) {
+                pages.push(p);
+            }
+        }
+    }
+    Ok(pages)
+}
+
+/// Merges the results of several keywords by taking their hits in turns,
+/// so that the top hit of the first keyword stays first and every
+/// keyword gets its best pages in early. Duplicates are dropped.
+fn interleave_results(results: Vec<Vec<WikiPage>>) -> Vec<WikiPage> {
+    let mut merged: Vec<WikiPage> = vec![];
+    let longest = results.iter().map(|r| r.len()).max().unwrap_or(0);
+    for rank in 0..longest {
+        for r in results.iter() {
+            if let Some(p) = r.get(rank) {
+                if !merged.iter().any(|q| q.page_id == p.page_id) {
+                    merged.push(p.clone());
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// The keyword with every phrase from the synonyms file replaced by each
+/// of its synonyms, in lower case.
+fn expand_synonyms(config: &Config, keyword: &str) -> Vec<String> {
+    let lower = keyword.to_lowercase();
+    let mut expansions: Vec<String> = vec![];
+    for (phrase, synonyms) in config.synonyms.iter() {
+        if !lower.contains(phrase.as_str()) {
+            continue;
+        }
+        for synonym in synonyms {
+            let e = lower.replace(phrase.as_str(), &synonym.to_lowercase());
+            if e != lower && !expansions.contains(&e) {
+                expansions.push(e);
+            }
+        }
+    }
+    expansions.sort();
+    expansions
+}
+
+fn is_disambiguation_title(title: &str) -> bool {
+    title.ends_with("(disambiguation)")
+}
+
+/// Returns the message content of choice `index` of a chat completion,
+/// failing if there is no such choice or it has no content.
+fn extract_answer(
+    response: &CreateChatCompletionResponse,
+    index: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let choice = response.choices.get(index).ok_or_else(|| {
+        format!(
+            "LLM response has no choice {} ({} choices received)",
+            index,
+            response.choices.len()
+        )
+    })?;
+    match &choice.message.content {
+        Some(content) => Ok(content.clone()),
+        None => Err(format!("Choice {} of the LLM response has no content", index).into()),
+    }
+}
+
+/// The instruction preceding the question in the answer prompt.
+fn answer_instruction(config: &Config) -> String {
+    let trust = if config.source_trust.is_empty() {
+        ""
+    } else {
+        "Every text is marked with a trust level. Where the texts contradict each other, prefer the one with the higher trust level. "
+    };
+    if let Some(schema) = &config.answer_schema {
+        return format!("{}Now answer the following question, using the information in the provided text. Reply with a JSON document only, which conforms to this JSON schema:\n{}\n\nThe question is:", trust, schema);
+    }
+    if config.extractive {
+        // Quotes only, so the reading level does not apply.
+        return format!("{}Now answer the following question only by quoting verbatim passages from the provided texts. Put every quote in double quotes and follow it with the title of its source in square brackets. Do not add any words of your own:", trust);
+    }
+    match config.reading_level.instruction() {
+        Some(level) => format!(
+            "{} {}Now answer the following question, using the information in the provided text:",
+            level, trust
+        ),
+        None => format!(
+            "{}Now answer the following question, using the information in the provided text:",
+            trust
+        ),
+    }
+}
+
+/// A placeholder of a prompt template.
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(question|pages)\}").unwrap());
+
+/// Fills the `{question}` and `{pages}` placeholders of a prompt template
+/// from `KEYWORD_PROMPT` or `ANSWER_PROMPT`, in one pass, so that a page
+/// mentioning a placeholder is left alone.
+fn fill_template(template: &str, question: &str, pages: &str) -> String {
+    PLACEHOLDER
+        .replace_all(template, |c: &regex::Captures| match &c[1] {
+            "question" => question.to_string(),
+            _ => pages.to_string(),
+        })
+        .into_owned()
+}
+
+/// The pages to give the LLM apart from the question, none if the
+/// `ANSWER_PROMPT` places them itself.
+fn separate_pages<'a>(config: &Config, pages: &'a [String]) -> &'a [String] {
+    match &config.answer_prompt {
+        Some(template) if template.contains("{pages}") => &[],
+        _ => pages,
+    }
+}
+
+/// The first question of the answer conversation, with the instruction.
+fn instructed_question(config: &Config, pages: &[String], question: &str) -> String {
+    match &config.answer_prompt {
+        Some(template) if template.contains("{question}") => {
+            fill_template(template, question, &pages.join("\n"))
+        }
+        Some(template) => format!(
+            "{} {}",
+            fill_template(template, question, &pages.join("\n")),
+            question
+        ),
+        None => format!("{} {}", answer_instruction(config), question),
+    }
+}
+
+/// A question together with the answer the LLM gave to it, used to carry
+/// an answer conversation over to follow-up questions.
+#[derive(Serialize, Deserialize)]
+pub struct Exchange {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Returns the questions of a conversation in order, each with the answer
+/// already given to it. The new question comes last and has no answer yet.
+fn conversation_turns<'a>(
+    history: &'a [Exchange],
+    question: &'a str,
+) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+    history
+        .iter()
+        .map(|e| (e.question.as_str(), Some(e.answer.as_str())))
+        .chain(std::iter::once((question, None)))
+}
+
+/// An answer stream which broke off after part of the answer arrived.
+#[derive(Debug)]
+pub struct StreamInterrupted {
+    /// The text received before the interruption.
+    pub partial: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for StreamInterrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "answer stream interrupted after {} characters: {}",
+            self.partial.len(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for StreamInterrupted {}
+
+/// Marks the streamed output as cut off and builds the error for it.
+fn stream_interrupted(
+    config: &Config,
+    partial: String,
+    reason: String,
+) -> Box<dyn std::error::Error> {
+    config.emit(PipelineEvent::AnswerChunk {
+        text: "\n[stream interrupted]\n".into(),
+    });
+    Box::new(StreamInterrupted { partial, reason })
+}
+
+/// A deterministic auxiliary LLM call with a system instruction and a
+/// user message, for the small helper steps around retrieval.
+async fn complete(
+    config: &Config,
+    system: &str,
+    user: &str,
+    max_tokens: u32,
+) -> Result<String, Box<dyn std::error::Error>> {
+    llm::backend(config)
+        .complete(system, user, max_tokens)
+        .await
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResult {
+    title: String,
+    pageid: u32,
+    #[serde(default)]
+    snippet: String,
+    size: Option<u64>,
+    wordcount: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchInfo {
+    totalhits: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryResult {
+    search: Vec<SearchResult>,
+    searchinfo: Option<SearchInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaResponse {
+    query: QueryResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WikiPage {
+    pub page_id: String,
+    pub title: String,
+    /// Search result snippet, with the API's HTML highlighting.
+    #[serde(default)]
+    pub snippet: String,
+    /// Timestamp of the latest revision, if it was fetched.
+    pub last_edited: Option<String>,
+    /// Size in bytes and number of words, as reported by the search.
+    pub size: Option<u64>,
+    pub wordcount: Option<u64>,
+}
+
+/// Search results asked for beyond the wanted pages, as replacements for
+/// pages which are skipped.
+const SEARCH_MARGIN: usize = 5;
+
+/// Search results asked for at least when the candidates are ranked
+/// again, the API's default.
+const RANKING_CANDIDATES: usize = 10;
+
+/// The most search results the API returns.
+const MAX_SEARCH_LIMIT: usize = 500;
+
+/// Wikipedia rejects search strings longer than this many characters.
+const MAX_SEARCH_LEN: usize = 300;
+
+/// Validates a search string before it is sent: control characters are
+/// an error, and overlong strings are cut at a word boundary with a
+/// warning.
+fn check_search_term(config: &Config, term: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let term = term.trim();
+    if term.is_empty() {
+        return Err("Empty search term".into());
+    }
+    if term.chars().any(|c| c.is_control()) {
+        return Err(format!("Search term contains control characters: {:?}", term).into());
+    }
+    if term.chars().count() <= MAX_SEARCH_LEN {
+        return Ok(term.to_string());
+    }
+    let mut cut: String = term.chars().take(MAX_SEARCH_LEN).collect();
+    if let Some(pos) = cut.rfind(char::is_whitespace) {
+        cut.truncate(pos);
+    }
+    progress!(
+        config,
+        "Warning: search term of {} characters is too long for Wikipedia, using '{}'.",
+        term.chars().count(),
+        cut
+    );
+    Ok(cut)
+}
+
+/// Titles may be at most 255 bytes long and must not contain some
+/// characters, which the API would otherwise report as an invalid title.
+fn check_title(title: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if title.len() > 255 {
+        return Err(format!("Title is longer than 255 bytes: {}", title).into());
+    }
+    if let Some(c) = title
+        .chars()
+        .find(|c| c.is_control() || "#<>[]|{}".contains(*c))
+    {
+        return Err(format!("Title contains the invalid character {:?}: {}", c, title).into());
+    }
+    Ok(())
+}
+
+async fn search_wikipedia(
+    config: &Config,
+    keyword: &str,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let keyword = check_search_term(config, keyword)?;
+    if let Some(dir) = &config.local_source {
+        return local::search(dir, &keyword, config.search_limit());
+    }
+    let base_url = wiki_api_url(config);
+
+    let limit = config.search_limit().to_string();
+    let params = [
+        ("action", "query"),
+        ("list", "search"),
+        ("srsearch", keyword.as_str()),
+        ("srlimit", limit.as_str()),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    let response: WikipediaResponse = serde_json::from_str(&body)?;
+    if let Some(info) = &response.query.searchinfo {
+        if info.totalhits == 0 {
+            progress!(config, "Wikipedia has no pages matching '{}'.", keyword);
+        } else if config.verbose {
+            eprintln!(
+                "Search for '{}' has {} hits, using the first {}.",
+                keyword,
+                info.totalhits,
+                response.query.search.len()
+            );
+        }
+    }
+
+    let pages: Vec<WikiPage> = response
+        .query
+        .search
+        .iter()
+        .map(|result| WikiPage {
+            page_id: result.pageid.to_string(),
+            title: result.title.to_string(),
+            snippet: result.snippet.clone(),
+            last_edited: None,
+            size: result.size,
+            wordcount: result.wordcount,
+        })
+        .collect();
+
+    Ok(pages)
+}
+
+#[derive(Deserialize, Debug)]
+struct CategoryMember {
+    pageid: u32,
+    title: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CategoryQuery {
+    #[serde(default)]
+    categorymembers: Vec<CategoryMember>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaCategoryResponse {
+    query: CategoryQuery,
+}
+
+/// Lists the articles in a Wikipedia category. A category which does not
+/// exist yields an empty list.
+async fn search_category(
+    config: &Config,
+    category: &str,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let base_url = wiki_api_url(config);
+    let title = format!(
+        "Category:{}",
+        category.trim().trim_start_matches("Category:")
+    );
+    check_title(&title)?;
+
+    let params = [
+        ("action", "query"),
+        ("list", "categorymembers"),
+        ("cmtitle", title.as_str()),
+        ("cmtype", "page"),
+        ("cmlimit", "20"),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    let response: WikipediaCategoryResponse = serde_json::from_str(&body)?;
+
+    Ok(response
+        .query
+        .categorymembers
+        .into_iter()
+        .map(|m| WikiPage {
+            page_id: m.pageid.to_string(),
+            title: m.title,
+            snippet: String::new(),
+            last_edited: None,
+            size: None,
+            wordcount: None,
+        })
+        .collect())
+}
+
+/// Uses the pages of the category, or a regular search if the category
+/// does not exist or is empty.
+async fn search_category_or_keyword(
+    config: &Config,
+    category: &str,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let pages = search_category(config, category).await?;
+    if !pages.is_empty() {
+        progress!(
+            config,
+            "Found {} pages in category '{}'.",
+            pages.len(),
+            category
+        );
+        return Ok(pages);
+    }
+    progress!(
+        config,
+        "Category '{}' does not exist or is empty, falling back to regular search.",
+        category
+    );
+    search_wikipedia(config, category).await
+}
+
+#[derive(Deserialize, Debug)]
+struct LinkedPage {
+    #[serde(default)]
+    pageid: u32,
+    title: String,
+    #[serde(default)]
+    missing: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct LinkedQuery {
+    #[serde(default)]
+    pages: Vec<LinkedPage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaLinksResponse {
+    /// Absent if the page has no links.
+    query: Option<LinkedQuery>,
+}
+
+/// Lists the existing articles the given page links to.
+async fn fetch_linked_pages(
+    config: &Config,
+    page_id: &str,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let base_url = wiki_api_url(config);
+
+    let params = [
+        ("action", "query"),
+        ("generator", "links"),
+        ("pageids", page_id),
+        ("gplnamespace", "0"),
+        ("gpllimit", "max"),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    let response: WikipediaLinksResponse = serde_json::from_str(&body)?;
+
+    Ok(response
+        .query
+        .map(|q| q.pages)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|p| !p.missing && p.pageid != 0)
+        .map(|p| WikiPage {
+            page_id: p.pageid.to_string(),
+            title: p.title,
+            snippet: String::new(),
+            last_edited: None,
+            size: None,
+            wordcount: None,
+        })
+        .collect())
+}
+
+fn significant_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 3)
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Picks up to `max` linked pages whose titles share words with the
+/// question, best matches first, together with the shared words.
+fn rank_linked_pages(
+    question: &str,
+    linked: Vec<WikiPage>,
+    known: &[WikiPage],
+    max: usize,
+) -> Vec<(WikiPage, Vec<String>)> {
+    let question_words = significant_words(question);
+    let mut ranked: Vec<(WikiPage, Vec<String>)> = linked
+        .into_iter()
+        .filter(|p| !known.iter().any(|k| k.page_id == p.page_id))
+        .map(|p| {
+            let mut shared: Vec<String> = significant_words(&p.title)
+                .into_iter()
+                .filter(|w| question_words.contains(w))
+                .collect();
+            shared.dedup();
+            (p, shared)
+        })
+        .filter(|(_, shared)| !shared.is_empty())
+        .collect();
+    // Stable, so equally good links keep the API's order:
+    ranked.sort_by_key(|(_, shared)| std::cmp::Reverse(shared.len()));
+    ranked.truncate(max);
+    ranked
+}
+
+#[derive(Deserialize, Debug)]
+struct Page {
+    pageid: u32,
+    /// Absent for missing pages, and for all but the first page when
+    /// whole articles are requested for several pages at once.
+    #[serde(default)]
+    extract: String,
+    #[serde(default)]
+    missing: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Redirect {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryPages {
+    pages: Vec<Page>,
+    #[serde(default)]
+    redirects: Vec<Redirect>,
+}
+
+/// Where a response of several extracts continues, for the pages the
+/// extract limit left out of it.
+#[derive(Deserialize, Debug)]
+struct ExtractContinue {
+    excontinue: serde_json::Value,
+    #[serde(rename = "continue", default)]
+    marker: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaExtractResponse {
+    query: QueryPages,
+    #[serde(rename = "continue")]
+    next: Option<ExtractContinue>,
+}
+
+/// Extracts shorter than this are checked for being a redirect stub.
+const REDIRECT_STUB_MAX_LEN: usize = 300;
+
+/// Whether an extract is (most likely) just the remains of a redirect
+/// page rather than actual article content.
+fn is_redirect_stub(extract: &str) -> bool {
+    let e = extract.trim().to_lowercase();
+    e.len() < REDIRECT_STUB_MAX_LEN
+        && (e.is_empty() || e.starts_with("redirect") || e.starts_with("#redirect"))
+}
+
+/// How disambiguation pages start their list of meanings, in the
+/// languages we know.
+const DISAMBIGUATION_MARKERS: &[&str] = &[
+    "may refer to:",
+    "may also refer to:",
+    "steht für:",
+    "peut désigner",
+    "puede referirse a:",
+    "può riferirsi a:",
+    "kan verwijzen naar:",
+];
+
+/// Whether an extract is a disambiguation page, which lists the meanings
+/// of a term instead of explaining one. The marker is in its first
+/// sentence, an article may well use the words further down.
+fn is_disambiguation_extract(extract: &str) -> bool {
+    let e: String = extract.chars().take(300).collect::<String>().to_lowercase();
+    DISAMBIGUATION_MARKERS.iter().any(|m| e.contains(m))
+}
+
+/// A page which cannot be used to answer from: it does not exist or is a
+/// disambiguation page.
+#[derive(Debug)]
+struct UnusablePage(&'static str);
+
+impl std::fmt::Display for UnusablePage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UnusablePage {}
+
+/// The query parameters for the plain text extracts of pages, or of
+/// just their lead sections with `intro_only`.
+fn extract_params(page_ids: &str, intro_only: bool) -> Vec<(&'static str, &str)> {
+    let mut params = vec![
+        ("action", "query"),
+        ("pageids", page_ids),
+        ("prop", "extracts"),
+        ("explaintext", "true"),
+        ("exlimit", "max"),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+    if intro_only {
+        params.push(("exintro", "true"));
+    }
+    params
+}
+
+async fn query_extract(
+    config: &Config,
+    page_id: &str,
+    follow_redirects: bool,
+    next: Option<&ExtractContinue>,
+) -> Result<WikipediaExtractResponse, Box<dyn std::error::Error>> {
+    let base_url = wiki_api_url(config);
+
+    let offset = next.map(|n| match &n.excontinue {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    });
+    let mut params = extract_params(page_id, config.intro_only);
+    if follow_redirects {
+        params.push(("redirects", "true"));
+    }
+    if let (Some(next), Some(offset)) = (next, &offset) {
+        params.push(("excontinue", offset.as_str()));
+        params.push(("continue", next.marker.as_str()));
+    }
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// The extract of a page, from the cache if it is there, and the title
+/// of the article whose extract it is if the page is a redirect.
+async fn download_wikipedia_page(
+    config: &Config,
+    page_id: &str,
+) -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    if let Some(dir) = &config.local_source {
+        return Ok((local::read(dir, page_id, config.intro_only)?, None));
+    }
+    if let Some(text) = cache::get(config, page_id) {
+        return Ok((text, None));
+    }
+    let (text, redirect) = fetch_wikipedia_page(config, page_id).await?;
+    // The text of a redirect is cached under the id of its target, so
+    // that the redirect is resolved, with its title, every time:
+    match &redirect {
+        Some((target_id, _)) => cache::put(config, target_id, &text),
+        None => cache::put(config, page_id, &text),
+    }
+    Ok((text, redirect.map(|(_, title)| title)))
+}
+
+/// The extract of a page, and the id and title of the target if the page
+/// is a redirect.
+async fn fetch_wikipedia_page(
+    config: &Config,
+    page_id: &str,
+) -> Result<(String, Option<(String, String)>), Box<dyn std::error::Error>> {
+    let response = query_extract(config, page_id, false, None).await?;
+
+    let Some(page) = response
+        .query
+        .pages
+        .iter()
+        .find(|p| p.pageid.to_string() == page_id && !p.missing)
+    else {
+        return Err(UnusablePage("the page does not exist").into());
+    };
+    if is_disambiguation_extract(&page.extract) {
+        return Err(UnusablePage("it is a disambiguation page").into());
+    }
+    if !is_redirect_stub(&page.extract) {
+        return Ok((page.extract.clone(), None));
+    }
+
+    // The selected page is a redirect, ask Wikipedia to resolve it and
+    // use the target article instead:
+    let resolved = query_extract(config, page_id, true, None).await?;
+    match (
+        resolved.query.redirects.first(),
+        resolved.query.pages.first(),
+    ) {
+        (Some(_), Some(target)) if is_disambiguation_extract(&target.extract) => {
+            Err(UnusablePage("it redirects to a disambiguation page").into())
+        }
+        (Some(r), Some(target)) => {
+            progress!(
+                config,
+                "Page '{}' is a redirect to '{}', using that page instead.",
+                r.from,
+                r.to
+            );
+            Ok((
+                target.extract.clone(),
+                Some((target.pageid.to_string(), r.to.clone())),
+            ))
+        }
+        _ => Ok((page.extract.clone(), None)),
+    }
+}
+
+/// The download of the first search result, started in the background
+/// while the candidates are shown.
+struct Prefetch {
+    page_id: String,
+    started: Instant,
+    handle: tokio::task::JoinHandle<Result<String, String>>,
+}
+
+/// Starts downloading the first candidate, so that the download overlaps
+/// with showing the search results instead of following it. Only done
+/// when that page is surely needed: no ranking step may replace it, and
+/// it is not read from the cache or a local source anyway.
+fn prefetch_first(config: &Config, pages: &[WikiPage]) -> Option<Prefetch> {
+    if config.rerank
+        || config.prefer_quality
+        || config.entity_boost
+        || config.select == PageSelection::Llm
+        || config.local_source.is_some()
+    {
+        return None;
+    }
+    let page_id = pages.first()?.page_id.clone();
+    if cache::contains(config, &page_id) {
+        return None;
+    }
+    let client = config.http.clone();
+    let url = wiki_api_url(config);
+    let (id, intro_only) = (page_id.clone(), config.intro_only);
+    let handle = tokio::spawn(async move {
+        let response = client
+            .get(&url)
+            .query(&extract_params(&id, intro_only))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let response = response.error_for_status().map_err(|e| e.to_string())?;
+        response.text().await.map_err(|e| e.to_string())
+    });
+    Some(Prefetch {
+        page_id,
+        started: Instant::now(),
+        handle,
+    })
+}
+
+/// The extract of the prefetched page, if it is among the pages to
+/// download and usable as it is. Otherwise, it is left to the normal
+/// download, with its retries and redirect handling.
+async fn take_prefetch(
+    config: &Config,
+    prefetch: Prefetch,
+    ids: &[&str],
+) -> Option<(String, String)> {
+    if !ids.contains(&prefetch.page_id.as_str()) {
+        prefetch.handle.abort();
+        return None;
+    }
+    let waiting = Instant::now();
+    let body = prefetch.handle.await.ok()?.ok()?;
+    if config.verbose {
+        eprintln!(
+            "Prefetched page {} in {} ms, of which the download phase waited {} ms.",
+            prefetch.page_id,
+            prefetch.started.elapsed().as_millis(),
+            waiting.elapsed().as_millis()
+        );
+    }
+    if retry::wiki_api_error(&body).is_some() {
+        return None;
+    }
+    let response: WikipediaExtractResponse = serde_json::from_str(&body).ok()?;
+    let page = response
+        .query
+        .pages
+        .iter()
+        .find(|p| p.pageid.to_string() == prefetch.page_id && !p.missing)?;
+    if is_redirect_stub(&page.extract) || is_disambiguation_extract(&page.extract) {
+        return None;
+    }
+    cache::put(config, &prefetch.page_id, &page.extract);
+    Some((prefetch.page_id, page.extract.clone()))
+}
+
+/// The outcome of downloading several pages in one request.
+struct BatchDownload {
+    /// Extracts by page id.
+    extracts: HashMap<String, String>,
+    /// Requested pages which came back missing, without an extract or as
+    /// a redirect stub.
+    failed: Vec<String>,
+    /// Requested pages the API left out because of its extract limit.
+    skipped: Vec<String>,
+}
+
+/// Downloads the extracts of several pages together, except for those in
+/// the cache, following the continuation where the extract limit leaves
+/// pages out. Pages which do not come with content are listed as failed
+/// rather than failing the whole batch, the caller decides how to
+/// replace them.
+async fn download_wikipedia_pages(
+    config: &Config,
+    page_ids: &[&str],
+) -> Result<BatchDownload, Box<dyn std::error::Error>> {
+    let mut batch = BatchDownload {
+        extracts: HashMap::new(),
+        failed: vec![],
+        skipped: vec![],
+    };
+    if let Some(dir) = &config.local_source {
+        for id in page_ids {
+            match local::read(dir, id, config.intro_only) {
+                Ok(text) => {
+                    batch.extracts.insert(id.to_string(), text);
+                }
+                Err(_) => batch.failed.push(id.to_string()),
+            }
+        }
+        return Ok(batch);
+    }
+    let mut missing: Vec<&str> = vec![];
+    for id in page_ids {
+        match cache::get(config, id) {
+            Some(text) => {
+                batch.extracts.insert(id.to_string(), text);
+            }
+            None => missing.push(id),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(batch);
+    }
+    // Whole articles come one per response, the other pages follow in
+    // the responses the continuation asks for:
+    let ids = missing.join("|");
+    let mut texts: HashMap<String, String> = HashMap::new();
+    let mut absent: Vec<String> = vec![];
+    let mut next = None;
+    let mut left_out = false;
+    loop {
+        let response = query_extract(config, &ids, false, next.as_ref()).await?;
+        let before = texts.len();
+        for page in response.query.pages {
+            if page.missing {
+                absent.push(page.pageid.to_string());
+            } else if !page.extract.is_empty() {
+                texts.insert(page.pageid.to_string(), page.extract);
+            }
+        }
+        next = response.next;
+        if next.is_none() {
+            break;
+        }
+        // A continuation without new extracts would never end:
+        if texts.len() == before {
+            left_out = true;
+            break;
+        }
+    }
+    for id in missing {
+        match texts.remove(id) {
+            Some(text) if !is_redirect_stub(&text) && !is_disambiguation_extract(&text) => {
+                cache::put(config, id, &text);
+                batch.extracts.insert(id.to_string(), text);
+            }
+            None if left_out && !absent.iter().any(|a| a == id) => {
+                batch.skipped.push(id.to_string())
+            }
+            _ => batch.failed.push(id.to_string()),
+        }
+    }
+    Ok(batch)
+}
+
+#[derive(Deserialize, Debug)]
+struct Revision {
+    timestamp: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RevisionsPage {
+    pageid: u32,
+    #[serde(default)]
+    revisions: Vec<Revision>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RevisionsQuery {
+    pages: Vec<RevisionsPage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaRevisionsResponse {
+    query: RevisionsQuery,
+}
+
+/// Fetches the timestamp of the latest revision of each given page, in a
+/// single request. Returns pairs of page id and timestamp.
+async fn fetch_last_edited(
+    config: &Config,
+    page_ids: &[&str],
+) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let base_url = wiki_api_url(config);
+    let ids = page_ids.join("|");
+
+    let params = [
+        ("action", "query"),
+        ("pageids", ids.as_str()),
+        ("prop", "revisions"),
+        ("rvprop", "timestamp"),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    let response: WikipediaRevisionsResponse = serde_json::from_str(&body)?;
+    Ok(response
+        .query
+        .pages
+        .into_iter()
+        .filter_map(|p| {
+            p.revisions
+                .into_iter()
+                .next()
+                .map(|r| (p.pageid.to_string(), r.timestamp))
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+struct Assessment {
+    #[serde(default)]
+    class: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AssessmentsPage {
+    #[serde(default)]
+    pageid: u32,
+    /// Assessments by WikiProject, absent for unassessed pages.
+    #[serde(default)]
+    pageassessments: HashMap<String, Assessment>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AssessmentsQuery {
+    #[serde(default)]
+    pages: Vec<AssessmentsPage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikipediaAssessmentsResponse {
+    query: AssessmentsQuery,
+}
+
+/// Ranks a quality class, higher is better. Pages without an assessment
+/// count like C-class articles, so that they are neither preferred nor
+/// pushed back.
+fn quality_rank(class: Option<&str>) -> u32 {
+    match class.map(|c| c.to_uppercase()).as_deref() {
+        Some("FA") => 7,
+        Some("FL") => 6,
+        Some("A") => 5,
+        Some("GA") => 4,
+        Some("B") => 3,
+        Some("C") | None => 2,
+        Some("START") => 1,
+        Some(_) => 0,
+    }
+}
+
+/// Fetches the best quality class any WikiProject gave each page. Pages
+/// without assessments are left out.
+async fn fetch_quality_classes(
+    config: &Config,
+    page_ids: &[&str],
+) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let base_url = wiki_api_url(config);
+    let ids = page_ids.join("|");
+
+    let params = [
+        ("action", "query"),
+        ("pageids", ids.as_str()),
+        ("prop", "pageassessments"),
+        ("palimit", "max"),
+        ("format", "json"),
+        ("formatversion", "2"),
+    ];
+
+    let body = wiki_get(config, &base_url, &params).await?;
+
+    if config.verbose {
+        eprintln!("Raw response: {}", body);
+    }
+
+    let response: WikipediaAssessmentsResponse = serde_json::from_str(&body)?;
+    Ok(response
+        .query
+        .pages
+        .into_iter()
+        .filter_map(|p| {
+            p.pageassessments
+                .into_values()
+                .map(|a| a.class)
+                .filter(|c| !c.is_empty())
+                .max_by_key(|c| quality_rank(Some(c)))
+                .map(|c| (p.pageid.to_string(), c))
+        })
+        .collect())
+}
+
+/// Moves candidates whose titles contain entities named in the question
+/// to the front, the more entities the further. The order is stable, so
+/// the ranking so far decides between pages with as many entities.
+fn boost_entities(config: &Config, question: &str, pages: &mut [WikiPage]) {
+    let found = entities::entities(question, &config.language);
+    if config.verbose {
+        eprintln!(
+            "Entities in the question: {}",
+            if found.is_empty() {
+                "none".to_string()
+            } else {
+                found.join(", ")
+            }
+        );
+    }
+    if found.is_empty() {
+        return;
+    }
+    let matches = |p: &WikiPage| {
+        let title = p.title.to_lowercase();
+        found
+            .iter()
+            .filter(|e| title.contains(&e.to_lowercase()))
+            .count()
+    };
+    let before: Vec<String> = pages.iter().map(|p| p.page_id.clone()).collect();
+    pages.sort_by_key(|p| std::cmp::Reverse(matches(p)));
+    if config.verbose {
+        let mut moved = false;
+        for (i, p) in pages.iter().enumerate() {
+            let old = before.iter().position(|id| *id == p.page_id).unwrap();
+            if old != i {
+                moved = true;
+                eprintln!(
+                    "  '{}' moved from rank {} to {}, its title names {} of the entities",
+                    p.title,
+                    old + 1,
+                    i + 1,
+                    matches(p)
+                );
+            }
+        }
+        if !moved {
+            eprintln!("  The entities did not change the ranking.");
+        }
+    }
+}
+
+/// Moves higher quality articles to the front of the candidates. The
+/// order is stable, so the search ranking decides between pages of the
+/// same quality. Without assessment data nothing changes.
+async fn prefer_quality(config: &Config, pages: &mut [WikiPage]) {
+    let ids: Vec<&str> = pages.iter().map(|p| p.page_id.as_str()).collect();
+    let classes = match fetch_quality_classes(config, &ids).await {
+        Ok(classes) => classes,
+        Err(e) => {
+            if config.verbose {
+                eprintln!("No quality assessments available: {}", e);
+            }
+            return;
+        }
+    };
+    let class_of = |p: &WikiPage| {
+        classes
+            .iter()
+            .find(|(id, _)| *id == p.page_id)
+            .map(|(_, c)| c.clone())
+    };
+    if config.verbose {
+        for p in pages.iter() {
+            if let Some(c) = class_of(p) {
+                eprintln!("Quality class of '{}': {}", p.title, c);
+            }
+        }
+    }
+    pages.sort_by_key(|p| std::cmp::Reverse(quality_rank(class_of(p).as_deref())));
+}
+
+/// Edits younger than this may not have been reviewed yet.
+const RECENT_EDIT_HOURS: i64 = 24;
+/// Articles not edited for this long may be outdated.
+const STALE_AFTER_DAYS: i64 = 2 * 365;
+
+/// Warns about pages which were edited very recently (possibly vandalism
+/// or an unsettled topic) or a very long time ago (possibly outdated).
+fn warn_about_edit_age(config: &Config, page: &WikiPage) {
+    let Some(ts) = &page.last_edited else {
+        return;
+    };
+    let Ok(edited) = chrono::DateTime::parse_from_rfc3339(ts) else {
+        return;
+    };
+    let age = chrono::Utc::now().signed_duration_since(edited);
+    if age.num_hours() < RECENT_EDIT_HOURS {
+        progress!(
+            config,
+            "Warning: '{}' was edited within the last {} hours, its content may not be stable.",
+            page.title,
+            RECENT_EDIT_HOURS
+        );
+    } else if age.num_days() > STALE_AFTER_DAYS {
+        progress!(
+            config,
+            "Warning: '{}' was last edited {} days ago, its content may be outdated.",
+            page.title,
+            age.num_days()
+        );
+    }
+}
+
+/// Formats a revision timestamp as a date for citations.
+fn edit_date(ts: &str) -> &str {
+    ts.split('T').next().unwrap_or(ts)
+}
+
+/// How a failure to connect shows up in the error chain of reqwest,
+/// including the copies of it inside the LLM client libraries.
+fn is_connect_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(e);
+    while let Some(err) = current {
+        if let Some(re) = err.downcast_ref::<reqwest::Error>() {
+            if re.is_connect() {
+                return true;
+            }
+        }
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            if matches!(
+                io.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::NotConnected
+            ) {
+                return true;
+            }
+        }
+        let text = err.to_string().to_lowercase();
+        if [
+            "dns error",
+            "failed to lookup address",
+            "connection refused",
+            "network is unreachable",
+            "error trying to connect",
+        ]
+        .iter()
+        .any(|t| text.contains(t))
+        {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Where the Ollama client connects to.
+pub(crate) const OLLAMA_URL: &str = "http://localhost:11434";
+
+#[derive(Deserialize)]
+struct OllamaTags {
+    #[serde(default)]
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+/// Checks whether Ollama answers, and with which models.
+async fn ollama_models(config: &Config) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let tags: OllamaTags = config
+        .http
+        .get(format!("{}/api/tags", OLLAMA_URL))
+        .send()
+        .await?
+        .json()
+        .await?;
+    Ok(tags.models.into_iter().map(|m| m.name).collect())
+}
+
+/// Whether Ollama is running and, if it is the configured provider, has
+/// the model.
+async fn ollama_status(config: &Config) -> Result<(), String> {
+    match available_models(config, LlmProvider::Ollama).await {
+        Ok(models) => {
+            let wanted = config.model.as_str();
+            if config.llm_server != LlmProvider::Ollama
+                || lists_model(&models, LlmProvider::Ollama, wanted)
+            {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Ollama is running at {}, but model {} is not installed (run 'ollama pull {}')",
+                    OLLAMA_URL, wanted, wanted
+                ))
+            }
+        }
+        Err(e) => Err(format!("Ollama is not reachable at {} ({})", OLLAMA_URL, e)),
+    }
+}
+
+/// Checks before the first question whether the provider of the
+/// configured model can be used: OpenAI and Anthropic need an API key,
+/// Ollama must be running and have the model. If not, the error lists
+/// what was checked for the providers and how to fix it.
+pub async fn validate_providers(config: &Config) -> Result<(), WikiRagError> {
+    let openai = match std::env::var("OPENAI_API_KEY") {
+        Ok(key) if !key.trim().is_empty() => Ok(()),
+        _ => Err("OPENAI_API_KEY is not set".to_string()),
+    };
+    let anthropic = match std::env::var("ANTHROPIC_API_KEY") {
+        Ok(key) if !key.trim().is_empty() => Ok(()),
+        _ => Err("ANTHROPIC_API_KEY is not set".to_string()),
+    };
+    let key = match config.llm_server {
+        LlmProvider::OpenAI => Some(&openai),
+        LlmProvider::Anthropic => Some(&anthropic),
+        LlmProvider::Ollama => None,
+    };
+    if key.is_some_and(|k| k.is_ok()) {
+        return check_model_listed(config).await;
+    }
+    // Ollama is only probed if it is configured, or for the diagnostic:
+    let ollama = ollama_status(config).await;
+    if config.llm_server == LlmProvider::Ollama && ollama.is_ok() {
+        return Ok(());
+    }
+    let status = |r: &Result<(), String>, ok: &str| match r {
+        Ok(()) => ok.to_string(),
+        Err(e) => e.clone(),
+    };
+    let mut msg = format!(
+        "model {} cannot be used. Checked:\n  OpenAI: {}\n  Anthropic: {}\n  Ollama: {}\n",
+        config.model,
+        status(&openai, "API key found"),
+        status(&anthropic, "API key found"),
+        status(&ollama, "running")
+    );
+    msg.push_str(match (config.llm_server, openai.is_ok(), ollama.is_ok()) {
+        (LlmProvider::OpenAI, _, true) => {
+            "Export your OpenAI API key with 'export OPENAI_API_KEY=sk-...', or use the local Ollama with AI_MODEL=llama3."
+        }
+        (LlmProvider::OpenAI, _, false) => {
+            "Export your OpenAI API key with 'export OPENAI_API_KEY=sk-...', or install and start Ollama (https://ollama.com) for local models."
+        }
+        (LlmProvider::Anthropic, _, _) => {
+            "Export your Anthropic API key with 'export ANTHROPIC_API_KEY=sk-ant-...' to use Claude models."
+        }
+        (LlmProvider::Ollama, true, _) => {
+            "Start Ollama and install the model, or use OpenAI with AI_MODEL=gpt-4o."
+        }
+        (LlmProvider::Ollama, false, _) => {
+            "Install and start Ollama (https://ollama.com), or set OPENAI_API_KEY to use OpenAI."
+        }
+    });
+    // A missing key is the only thing checked for the API providers:
+    Err(match config.llm_server {
+        LlmProvider::Ollama => WikiRagError::Provider(msg),
+        _ => WikiRagError::MissingApiKey(msg),
+    })
+}
+
+/// Checks that the API provider of the configured model lists it. If the
+/// list cannot be fetched, the model is given the benefit of the doubt.
+async fn check_model_listed(config: &Config) -> Result<(), WikiRagError> {
+    if config.llm_server == LlmProvider::Ollama {
+        return Ok(());
+    }
+    match model_available(config).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(WikiRagError::Provider(format!(
+            "model {} is not available, the provider offers:\n{}",
+            config.model,
+            supported_models()
+        ))),
+        Err(e) => {
+            progress!(
+                config,
+                "Could not check whether model {} is available: {}",
+                config.model,
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Prints the message for an error, with advice for the common causes,
+/// and returns the exit code for it.
+pub fn report_error(e: &WikiRagError) -> i32 {
+    match e {
+        WikiRagError::Offline(_) => {
+            let text = e.to_string();
+            if text.contains("localhost") || text.contains("127.0.0.1") {
+                eprintln!(
+                    "Error: could not connect to the local Ollama server, is it running?\n({})",
+                    e
+                );
+            } else {
+                eprintln!(
+                    "Error: Network appears to be offline ({}).\nWithout network, use a local model with Ollama, for example AI_MODEL=llama3.",
+                    e
+                );
+            }
+        }
+        WikiRagError::ModelUnavailable(_) => {
+            eprintln!(
+                "Error: the model is not available from its provider, it may have been retired ({}).
+Choose another one with AI_MODEL, the supported models are:
+{}Set MODEL_FALLBACK=1 to switch to {} automatically.",
+                e,
+                supported_models(),
+                DEFAULT_MODEL
+            );
+        }
+        // Clap formats its messages, and shows the help, itself:
+        WikiRagError::Usage(e) => {
+            let _ = e.print();
+        }
+        e => eprintln!("Error: {}", e),
+    }
+    e.exit_code()
+}
+
+/// Whether the provider rejected the model as unknown or retired.
+fn is_model_unavailable(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(e);
+    while let Some(err) = current {
+        if let Some(async_openai::error::OpenAIError::ApiError(api)) =
+            err.downcast_ref::<async_openai::error::OpenAIError>()
+        {
+            if api.code.as_deref() == Some("model_not_found") {
+                return true;
+            }
+        }
+        if let Some(he) = err.downcast_ref::<retry::HttpStatusError>() {
+            // Anthropic answers 404 only for an unknown model:
+            if he.status == 404 {
+                return true;
+            }
+        }
+        let text = err.to_string().to_lowercase();
+        if text.contains("model")
+            && [
+                "not found",
+                "does not exist",
+                "deprecated",
+                "decommissioned",
+            ]
+            .iter()
+            .any(|t| text.contains(t))
+        {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Checks with the provider whether it serves the configured model.
+async fn model_available(config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    let models = available_models(config, config.llm_server).await?;
+    Ok(lists_model(&models, config.llm_server, &config.model))
+}
+
+/// With `MODEL_FALLBACK`, switches to the default model if the provider
+/// does not serve the configured one.
+pub async fn fall_back_if_unavailable(config: &mut Config) {
+    if !config.model_fallback || config.model == DEFAULT_MODEL {
+        return;
+    }
+    match model_available(config).await {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!(
+                "Warning: model {} is not available, falling back to {}.",
+                config.model, DEFAULT_MODEL
+            );
+            config.model = DEFAULT_MODEL.into();
+            config.llm_server = provider_for_model(DEFAULT_MODEL).unwrap();
+        }
+        Err(e) => progress!(
+            config,
+            "Could not check whether model {} is available: {}",
+            config.model,
+            e
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSource<'a> {
+    page_id: &'a str,
+    title: &'a str,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_edited: Option<&'a str>,
+}
+
+/// The tokens used so far and what they cost in dollars.
+#[derive(Serialize)]
+struct JsonUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+}
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    question: &'a str,
+    keywords: &'a str,
+    answer: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    translations: &'a BTreeMap<String, String>,
+    pages: Vec<JsonSource<'a>>,
+    usage: JsonUsage,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    evidence: &'a [Evidence],
+}
+
+/// Where the answer goes: always stdout, and optionally also a file.
+/// The file receives the same text with terminal control sequences
+/// removed, so it stays clean when stdout is decorated.
+pub struct AnswerOutput {
+    file: Option<std::fs::File>,
+}
+
+impl AnswerOutput {
+    pub fn new(config: &Config) -> Result<AnswerOutput, WikiRagError> {
+        let file = match &config.output_file {
+            Some(path) => Some(std::fs::File::create(path).map_err(|e| {
+                WikiRagError::Output(format!("cannot create output file {}: {}", path, e).into())
+            })?),
+            None => None,
+        };
+        Ok(AnswerOutput { file })
+    }
+
+    /// Writes a piece of output to stdout and the output file, flushing
+    /// both so that streamed text shows up immediately.
+    pub fn emit(&mut self, text: &str) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+        if let Some(f) = self.file.as_mut() {
+            let res = f
+                .write_all(strip_control_sequences(text).as_bytes())
+                .and_then(|_| f.flush());
+            if let Err(e) = res {
+                eprintln!("Error writing output file: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// Removes ANSI escape sequences and other control characters except
+/// newlines and tabs.
+fn strip_control_sequences(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequences end with a character in the range '@'..='~'.
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            } else {
+                chars.next();
+            }
+        } else if !c.is_control() || c == '\n' || c == '\t' {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Footer used with `ANSWER_FOOTER=1`. Placeholders `{model}`, `{date}`
+/// and `{sources}` (space separated URLs) are filled in.
+pub const DEFAULT_FOOTER: &str = "-- Answered by {model} on {date} from {sources}";
+
+fn answer_footer(config: &Config, template: &str, pages: &[WikiPage]) -> String {
+    let sources = pages
+        .iter()
+        .map(|p| wiki_url(&config.language, &p.title))
+        .collect::<Vec<_>>()
+        .join(" ");
+    template
+        .replace("{model}", &config.model)
+        .replace(
+            "{date}",
+            &chrono::Local::now().format("%Y-%m-%d").to_string(),
+        )
+        .replace("{sources}", &sources)
+}
+
+/// A BibTeX entry for a page, keyed by its page id so that the key stays
+/// the same when the title changes.
+fn bibtex_entry(config: &Config, page: &WikiPage) -> String {
+    let today = chrono::Local::now();
+    format!(
+        "@misc{{wikipedia_{},\n  author = {{{{Wikipedia contributors}}}},\n  title = {{{}}},\n  howpublished = {{Wikipedia, The Free Encyclopedia}},\n  year = {{{}}},\n  url = {{{}}},\n  note = {{Accessed {}}}\n}}",
+        page.page_id,
+        page.title,
+        page.last_edited
+            .as_deref()
+            .and_then(|ts| edit_date(ts).get(..4))
+            .map(|year| year.to_string())
+            .unwrap_or_else(|| today.format("%Y").to_string()),
+        wiki_url(&config.language, &page.title),
+        today.format("%Y-%m-%d")
+    )
+}
+
+/// An APA style reference for a page.
+fn apa_citation(config: &Config, page: &WikiPage) -> String {
+    let date = match page
+        .last_edited
+        .as_deref()
+        .and_then(|ts| chrono::NaiveDate::parse_from_str(edit_date(ts), "%Y-%m-%d").ok())
+    {
+        Some(d) => d.format("%Y, %B %-d").to_string(),
+        None => "n.d.".to_string(),
+    };
+    format!(
+        "Wikipedia contributors. ({}). {}. In Wikipedia, The Free Encyclopedia. Retrieved {}, from {}",
+        date,
+        page.title,
+        chrono::Local::now().format("%B %-d, %Y"),
+        wiki_url(&config.language, &page.title)
+    )
+}
+
+/// Prints the answer in the configured format. A streamed answer has
+/// been printed already, so `answer` is empty then.
+pub fn print_answer(
+    config: &Config,
+    out: &mut AnswerOutput,
+    report: &AnswerReport,
+    answer: &str,
+) -> Result<(), WikiRagError> {
+    let pages = &report.pages;
+    let evidence = &report.evidence;
+    match config.format {
+        OutputFormat::Text => {
+            out.emit(&format!("{} \n\n", answer));
+            for (code, translation) in report.translations.iter() {
+                out.emit(&format!(
+                    "***{}***:\n{}\n\n",
+                    lang::language_name(code),
+                    translation
+                ));
+            }
+            if let Some(c) = report.confidence {
+                out.emit(&format!("***Confidence***: {:.2}\n\n", c));
+            }
+            out.emit("***Links***:\n");
+            match config.citation_style {
+                CitationStyle::Plain => {
+                    for p in pages.iter() {
+                        match &p.last_edited {
+                            Some(ts) => out.emit(&format!(
+                                "{} (last edited {})\n",
+                                wiki_url(&config.language, &p.title),
+                                edit_date(ts)
+                            )),
+                            None => {
+                                out.emit(&format!("{}\n", wiki_url(&config.language, &p.title)))
+                            }
+                        }
+                    }
+                }
+                CitationStyle::Bibtex => {
+                    for p in pages.iter() {
+                        out.emit(&format!("{}\n", bibtex_entry(config, p)));
+                    }
+                }
+                CitationStyle::Apa => {
+                    for p in pages.iter() {
+                        out.emit(&format!("{}\n", apa_citation(config, p)));
+                    }
+                }
+            }
+            if !evidence.is_empty() {
+                out.emit("\n***Evidence***:\n");
+                for e in evidence.iter() {
+                    out.emit(&format!(
+                        "- {}\n  {}: \"{}\"\n",
+                        e.claim, e.title, e.passage
+                    ));
+                }
+            }
+        }
+        OutputFormat::Markdown => {
+            out.emit(&format!("{}\n\n", answer));
+            for (code, translation) in report.translations.iter() {
+                out.emit(&format!(
+                    "## {}\n\n{}\n\n",
+                    lang::language_name(code),
+                    translation
+                ));
+            }
+            if let Some(c) = report.confidence {
+                out.emit(&format!("**Confidence:** {:.2}\n\n", c));
+            }
+            out.emit("## Sources\n\n");
+            match config.citation_style {
+                CitationStyle::Plain => {
+                    for p in pages.iter() {
+                        match &p.last_edited {
+                            Some(ts) => out.emit(&format!(
+                                "- [{}]({}) (last edited {})\n",
+                                p.title,
+                                wiki_url(&config.language, &p.title),
+                                edit_date(ts)
+                            )),
+                            None => out.emit(&format!(
+                                "- [{}]({})\n",
+                                p.title,
+                                wiki_url(&config.language, &p.title)
+                            )),
+                        }
+                    }
+                }
+                CitationStyle::Bibtex => {
+                    out.emit("```bibtex\n");
+                    for p in pages.iter() {
+                        out.emit(&format!("{}\n", bibtex_entry(config, p)));
+                    }
+                    out.emit("```\n");
+                }
+                CitationStyle::Apa => {
+                    for p in pages.iter() {
+                        out.emit(&format!("- {}\n", apa_citation(config, p)));
+                    }
+                }
+            }
+            if !evidence.is_empty() {
+                out.emit("\n## Evidence\n\n");
+                for e in evidence.iter() {
+                    out.emit(&format!(
+                        "- {}\n\n  > {} ([{}]({}))\n\n",
+                        e.claim,
+                        e.passage,
+                        e.title,
+                        wiki_url(&config.language, &e.title)
+                    ));
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let json = JsonOutput {
+                question: &report.question,
+                keywords: &report.keywords,
+                answer,
+                confidence: report.confidence,
+                translations: &report.translations,
+                pages: pages
+                    .iter()
+                    .map(|p| JsonSource {
+                        page_id: &p.page_id,
+                        title: &p.title,
+                        url: wiki_url(&config.language, &p.title),
+                        last_edited: p.last_edited.as_deref(),
+                    })
+                    .collect(),
+                usage: JsonUsage {
+                    prompt_tokens: config.usage.prompt_tokens(),
+                    completion_tokens: config.usage.completion_tokens(),
+                    cost: config.usage.cost(),
+                },
+                evidence,
+            };
+            let s =
+                serde_json::to_string_pretty(&json).map_err(|e| WikiRagError::Output(e.into()))?;
+            out.emit(&format!("{}\n", s));
+        }
+    }
+    // In JSON mode the same metadata is available in the fields.
+    if config.format != OutputFormat::Json {
+        if let Some(template) = &config.answer_footer {
+            out.emit(&format!("\n{}\n", answer_footer(config, template, pages)));
+        }
+    }
+    Ok(())
+}
+
+/// Everything retrieved from Wikipedia for one question. It is filled in
+/// stage by stage, so that when a later stage fails, the partial results
+/// can still be shown for diagnosis.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retrieval {
+    pub keyword_chain: Vec<String>,
+    pub keywords: String,
+    pub pages: Vec<WikiPage>,
+    pub page_strings: Vec<String>,
+}
+
+impl Retrieval {
+    /// Prints whatever has been gathered so far to stderr.
+    fn print_partial(&self, config: &Config) {
+        eprintln!("\nResults gathered before the failure:");
+        if self.keyword_chain.is_empty() {
+            eprintln!("  Keywords: none derived");
+            return;
+        }
+        eprintln!("  Keywords: {}", self.keyword_chain.join(", "));
+        if !self.keywords.is_empty() {
+            eprintln!("  Searched for: {}", self.keywords);
+        }
+        for (i, p) in self.pages.iter().enumerate() {
+            match self.page_strings.get(i) {
+                Some(text) => eprintln!(
+                    "  Page {} '{}' (downloaded, size {}): {}",
+                    p.page_id,
+                    p.title,
+                    text.len(),
+                    wiki_url(&config.language, &p.title)
+                ),
+                None => eprintln!(
+                    "  Page {} '{}': {}",
+                    p.page_id,
+                    p.title,
+                    wiki_url(&config.language, &p.title)
+                ),
+            }
+        }
+    }
+
+    /// Adds the downloaded pages of another retrieval which are not
+    /// there yet. Candidates which were not downloaded are dropped, so
+    /// that `pages` and `page_strings` stay aligned.
+    fn absorb(&mut self, other: Retrieval) {
+        self.pages.truncate(self.page_strings.len());
+        self.keyword_chain.extend(other.keyword_chain);
+        if !other.keywords.is_empty() {
+            if !self.keywords.is_empty() {
+                self.keywords.push_str("; ");
+            }
+            self.keywords.push_str(&other.keywords);
+        }
+        for (page, text) in other.pages.into_iter().zip(other.page_strings) {
+            if !self.pages.iter().any(|p| p.page_id == page.page_id) {
+                self.pages.push(page);
+                self.page_strings.push(text);
+            }
+        }
+    }
+
+    /// Passes the result on, showing the partial results first if it is
+    /// a failure.
+    fn check<T>(&self, config: &Config, r: Result<T, WikiRagError>) -> Result<T, WikiRagError> {
+        if r.is_err() {
+            self.print_partial(config);
+        }
+        r
+    }
+}
+
+/// Lets the user accept the derived keyword or replace it before searching.
+fn confirm_keyword(config: &Config, derived: String) -> String {
+    prompt!(
+        config,
+        "Search for [{}]? Enter to accept or type a replacement:",
+        derived
+    );
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line).is_err() {
+        return derived;
+    }
+    let line = line.trim();
+    if line.is_empty() {
+        derived
+    } else {
+        line.to_string()
+    }
+}
+
+fn remove_skipped_titles(config: &Config, pages: &mut Vec<WikiPage>) {
+    pages.retain(|p| {
+        match config
+            .skip_title_patterns
+            .iter()
+            .find(|re| re.is_match(&p.title))
+        {
+            Some(re) => {
+                progress!(
+                    config,
+                    "Skipping search result '{}' (matches pattern '{}')",
+                    p.title,
+                    re.as_str()
+                );
+                false
+            }
+            None => true,
+        }
+    });
+}
+
+/// An HTML tag, like those of the match highlighting.
+static HTML_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+/// Plain text from the HTML of a search snippet: without the tags of
+/// the match highlighting, with the common entities decoded and the
+/// whitespace collapsed.
+fn strip_html_tags(text: &str) -> String {
+    let text = HTML_TAG.replace_all(text, "");
+    let text = text
+        .replace("&quot;", "\"")
+        .replace("&#039;", "'")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Sorts the candidates by the similarity of their title and snippet to
+/// the question, and drops those below `RERANK_MIN_SIMILARITY`. On
+/// failure the search order is kept.
+async fn rerank_pages(config: &Config, question: &str, pages: &mut Vec<WikiPage>) {
+    let mut texts = vec![question.to_string()];
+    texts.extend(
+        pages
+            .iter()
+            .map(|p| format!("{}: {}", p.title, strip_html_tags(&p.snippet))),
+    );
+    let embeddings = match llm::embedder(config).embed(texts).await {
+        Ok(e) if e.len() == pages.len() + 1 => e,
+        Ok(_) => {
+            progress!(config, "Reranking failed: wrong number of embeddings.");
+            return;
+        }
+        Err(e) => {
+            progress!(config, "Reranking failed, keeping the search order: {}", e);
+            return;
+        }
+    };
+    let mut scored: Vec<(f64, WikiPage)> = embeddings[1..]
+        .iter()
+        .map(|e| cosine_similarity(&embeddings[0], e))
+        .zip(pages.drain(..))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (score, page) in scored {
+        match config.rerank_min_similarity {
+            Some(min) if score < min => {
+                if config.verbose {
+                    eprintln!(
+                        "Dropping '{}', similarity {:.3} is below {}",
+                        page.title, score, min
+                    );
+                }
+            }
+            _ => {
+                if config.verbose {
+                    eprintln!("Similarity of '{}': {:.3}", page.title, score);
+                }
+                pages.push(page);
+            }
+        }
+    }
+    if pages.is_empty() {
+        progress!(
+            config,
+            "No candidate page is similar enough to the question."
+        );
+    }
+}
+
+/// The page numbers in the reply of the LLM.
+static INTEGER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
+
+/// Asks the LLM which of the candidate pages to download, based only on
+/// their titles and snippets, and keeps just the selected ones (best
+/// first). On any failure the search order is kept.
+async fn select_pages_with_llm(config: &Config, question: &str, pages: &mut Vec<WikiPage>) {
+    let system = format!(
+        "You choose Wikipedia pages for answering a question. Given the question and a numbered list of candidate pages with title and snippet, pick up to {} pages which most likely contain the answer. Respond with the numbers of the chosen pages, most relevant first, separated by commas, on the first line, and a one sentence rationale on the second line.",
+        config.pages_wanted()
+    );
+    let mut user = format!("Question: {}\n\nCandidates:\n", question);
+    for (i, p) in pages.iter().enumerate() {
+        user.push_str(&format!(
+            "{}. {}: {}\n",
+            i + 1,
+            p.title,
+            strip_html_tags(&p.snippet)
+        ));
+    }
+
+    progress!(config, "Asking the LLM to select pages to download...");
+    let response = match complete(config, &system, &user, 100).await {
+        Ok(r) => r,
+        Err(e) => {
+            progress!(config, "Page selection failed ({}), using search order.", e);
+            return;
+        }
+    };
+    let mut lines = response.lines();
+    let chosen: Vec<usize> = INTEGER
+        .find_iter(lines.next().unwrap_or(""))
+        .filter_map(|m| m.as_str().parse::<usize>().ok())
+        .filter(|n| *n >= 1 && *n <= pages.len())
+        .map(|n| n - 1)
+        .collect();
+    if config.verbose {
+        eprintln!("Page selection by the LLM: {:?}", chosen);
+        eprintln!("Rationale: {}", lines.collect::<Vec<_>>().join(" "));
+    }
+    if chosen.is_empty() {
+        progress!(config, "The LLM selected no pages, using search order.");
+        return;
+    }
+
+    let mut candidates: Vec<Option<WikiPage>> = pages.drain(..).map(Some).collect();
+    for i in chosen {
+        if let Some(p) = candidates[i].take() {
+            pages.push(p);
+        }
+    }
+    progress!(
+        config,
+        "Selected pages: {}",
+        pages
+            .iter()
+            .map(|p| p.title.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+/// Fails for questions which are empty, so that no LLM tokens are wasted
+/// on them.
+fn check_question(question: &str) -> Result<(), WikiRagError> {
+    if question.trim().is_empty() {
+        Err(WikiRagError::NoQuestion)
+    } else {
+        Ok(())
+    }
+}
+
+/// Keywords without an LLM: the question minus the stopwords of its
+/// language, or of the configured stopword file.
+fn local_keywords(config: &Config, question: &str) -> String {
+    let words = match &config.stopwords {
+        Some(words) => words.clone(),
+        None => stopwords::builtin(lang::detect_language(question).unwrap_or(&config.language)),
+    };
+    stopwords::keywords(question, &words)
+}
+
+/// Rewrites a conversational question into a short search query. On
+/// failure the original question is used.
+async fn rewrite_question(config: &Config, question: &str) -> String {
+    let system = "Rewrite the user's question into a concise, search-optimized query of a few words, for example \"I was wondering, who was that guy who invented the telephone?\" becomes \"inventor of the telephone\". Keep names and the language of the question. Reply with the query only.";
+    match complete(config, system, question, 64).await {
+        Ok(rewritten) if !rewritten.trim().is_empty() => {
+            let rewritten = rewritten.trim().trim_matches('"').to_string();
+            if config.verbose {
+                eprintln!("Original question: {}", question);
+                eprintln!("Rewritten for search: {}", rewritten);
+            }
+            rewritten
+        }
+        Ok(_) => question.to_string(),
+        Err(e) => {
+            progress!(
+                config,
+                "Could not rewrite the question ({}), searching with it as is.",
+                e
+            );
+            question.to_string()
+        }
+    }
+}
+
+/// Asks the LLM whether the pages downloaded so far are enough for a
+/// confident answer. If that cannot be told, more pages are downloaded.
+async fn can_answer(config: &Config, question: &str, pages: &[String]) -> bool {
+    let system = "You are given texts from Wikipedia and a question. Can the question be answered confidently and completely from these texts alone? Reply with yes or no only.";
+    let user = format!("{}\n\nQuestion: {}", pages.join("\n"), question);
+    match complete(config, system, &user, 4).await {
+        Ok(reply) => {
+            let enough = reply.trim().to_lowercase().starts_with("yes");
+            if config.verbose {
+                eprintln!(
+                    "Enough for an answer with {} pages: {}",
+                    pages.len(),
+                    reply.trim()
+                );
+            }
+            enough
+        }
+        Err(e) => {
+            progress!(config, "Could not check whether the pages suffice: {}", e);
+            false
+        }
+    }
+}
+
+/// At most this many sub-questions are looked up for one question.
+const MAX_SUB_QUESTIONS: usize = 4;
+
+/// Splits a compound question into sub-questions which can be looked up
+/// on their own. On failure the question is kept whole.
+async fn decompose_question(config: &Config, question: &str) -> Vec<String> {
+    let system = "Split the user's question into the separate sub-questions it consists of, so that each can be looked up on its own in an encyclopedia. For example \"What is X and how does it relate to Y?\" becomes \"What is X?\" and \"How does X relate to Y?\". Reply with one sub-question per line and nothing else. If the question has only one part, repeat it unchanged.";
+    match complete(config, system, question, 200).await {
+        Ok(reply) => reply
+            .lines()
+            .map(|l| {
+                l.trim()
+                    .trim_start_matches(|c: char| c.is_ascii_digit() || ".-*) ".contains(c))
+                    .to_string()
+            })
+            .filter(|l| !l.is_empty())
+            .take(MAX_SUB_QUESTIONS)
+            .collect(),
+        Err(e) => {
+            progress!(
+                config,
+                "Could not decompose the question ({}), retrieving for it as a whole.",
+                e
+            );
+            vec![]
+        }
+    }
+}
+
+/// Retrieves the pages for the question. With `DECOMPOSE`, a compound
+/// question is split and the pages found for its parts are combined.
+pub async fn retrieve(config: &Config, question: &str) -> Result<Retrieval, WikiRagError> {
+    if !config.decompose {
+        return retrieve_single(config, question).await;
+    }
+    check_question(question)?;
+    let subs = decompose_question(config, question).await;
+    if subs.len() < 2 {
+        return retrieve_single(config, question).await;
+    }
+    progress!(
+        config,
+        "\nThe question has {} parts, retrieving pages for each.",
+        subs.len()
+    );
+    let mut r = Retrieval::default();
+    for sub in subs.iter() {
+        let part = retrieve_single(config, sub).await?;
+        if config.verbose {
+            eprintln!(
+                "Sub-question '{}': keywords {}",
+                sub,
+                part.keyword_chain.join(", ")
+            );
+        }
+        r.absorb(part);
+    }
+    Ok(r)
+}
+
+async fn retrieve_single(config: &Config, question: &str) -> Result<Retrieval, WikiRagError> {
+    check_question(question)?;
+    // Only retrieval uses the rewritten question, the answer is given to
+    // the original one.
+    let rewritten;
+    let question = if config.rewrite_question {
+        rewritten = rewrite_question(config, question).await;
+        rewritten.as_str()
+    } else {
+        question
+    };
+    if config.keyword_method == KeywordMethod::Local {
+        progress!(
+            config,
+            "\nDeriving keywords locally by removing stopwords..."
+        );
+    } else {
+        progress!(
+            config,
+            "\nPerforming keyword derivation using LLM model {}...",
+            config.model
+        );
+    }
+    let res = match config.keyword_method {
+        KeywordMethod::Local => Ok(local_keywords(config, question)),
+        KeywordMethod::Llm => llm::backend(config).extract_keywords(question).await,
+    };
+    let mut r = Retrieval::default();
+    let response: String = r.check(config, res.map_err(WikiRagError::llm))?;
+    let mut chain = keyword_chain(config, &response);
+    r.keyword_chain = chain.iter().flatten().cloned().collect();
+    config.emit(PipelineEvent::KeywordsDerived {
+        keywords: r.keyword_chain.clone(),
+    });
+    if config.interactive {
+        let first = r.keyword_chain.first().cloned().unwrap_or_default();
+        let chosen = confirm_keyword(config, first.clone());
+        if chosen != first {
+            let mut together = chain.first().cloned().unwrap_or_default();
+            match together.first_mut() {
+                Some(k) => *k = chosen.clone(),
+                None => together.push(chosen.clone()),
+            }
+            chain.insert(0, together);
+            r.keyword_chain.insert(0, chosen);
+        }
+    }
+
+    // Walk the keyword chain until one entry yields usable results:
+    for (i, keywords) in chain.iter().enumerate() {
+        progress!(
+            config,
+            "\nPerforming lookup in Wikipedia using '{}'...",
+            keywords.join("', '")
+        );
+        let mut results = vec![];
+        for k in keywords.iter() {
+            let res = search_keyword(config, k).await;
+            results.push(r.check(config, res.map_err(WikiRagError::search))?);
+        }
+        r.pages = interleave_results(results);
+        r.keywords = keywords.join(", ");
+        remove_skipped_titles(config, &mut r.pages);
+        if r.pages.iter().any(|p| !is_disambiguation_title(&p.title)) {
+            if i > 0 {
+                progress!(
+                    config,
+                    "Keyword '{}' (number {} of {}) found usable results.",
+                    r.keywords,
+                    i + 1,
+                    chain.len()
+                );
+            }
+            break;
+        }
+        progress!(config, "No usable search results for '{}'.", r.keywords);
+    }
+    let mut tried = r.keyword_chain.clone();
+    let mut broadened = 0;
+    while !r.pages.iter().any(|p| !is_disambiguation_title(&p.title))
+        && config.keyword_method == KeywordMethod::Llm
+        && broadened < config.broaden_retries
+    {
+        broadened += 1;
+        let keyword = match broader_keyword(config, question, &tried).await {
+            Ok(k) if !k.is_empty() && !tried.iter().any(|t| t.eq_ignore_ascii_case(&k)) => k,
+            Ok(_) => break,
+            Err(e) => {
+                progress!(config, "Could not get a broader keyword: {}", e);
+                break;
+            }
+        };
+        progress!(
+            config,
+            "\nNothing found, trying the broader keyword '{}' ({} of {})...",
+            keyword,
+            broadened,
+            config.broaden_retries
+        );
+        let res = search_keyword(config, &keyword).await;
+        r.pages = r.check(config, res.map_err(WikiRagError::search))?;
+        remove_skipped_titles(config, &mut r.pages);
+        r.keywords = keyword.clone();
+        r.keyword_chain.push(keyword.clone());
+        tried.push(keyword);
+    }
+    if r.pages.is_empty() {
+        r.check::<()>(
+            config,
+            Err(WikiRagError::Search(
+                format!(
+                    "no Wikipedia page was found for '{}', try rephrasing the question",
+                    tried.join("', '")
+                )
+                .into(),
+            )),
+        )?;
+    }
+    let prefetch = prefetch_first(config, &r.pages);
+    config.emit(PipelineEvent::SearchCompleted {
+        keyword: r.keywords.clone(),
+        pages: r.pages.clone(),
+    });
+
+    if config.rerank && !r.pages.is_empty() {
+        rerank_pages(config, question, &mut r.pages).await;
+    }
+
+    if config.prefer_quality && r.pages.len() > 1 {
+        prefer_quality(config, &mut r.pages).await;
+    }
+
+    if config.entity_boost && r.pages.len() > 1 {
+        boost_entities(config, question, &mut r.pages);
+    }
+
+    if config.select == PageSelection::Llm && r.pages.len() > 1 {
+        select_pages_with_llm(config, question, &mut r.pages).await;
+    }
+
+    // Download pages, as many as the API allows in one request. Adaptive
+    // context starts with one and fetches the others only when needed:
+    let batch_size = if config.adaptive_context {
+        1
+    } else {
+        config.pages_wanted()
+    };
+    let wanted: Vec<String> = r
+        .pages
+        .iter()
+        .take(batch_size)
+        .map(|p| p.page_id.clone())
+        .collect();
+    let ids: Vec<&str> = wanted.iter().map(|id| id.as_str()).collect();
+    let prefetched = match prefetch {
+        Some(p) => take_prefetch(config, p, &ids).await,
+        None => None,
+    };
+    let rest: Vec<&str> = ids
+        .iter()
+        .copied()
+        .filter(|id| prefetched.as_ref().map(|(p, _)| p.as_str()) != Some(*id))
+        .collect();
+    let res = download_wikipedia_pages(config, &rest).await;
+    let mut batch = r.check(config, res.map_err(WikiRagError::download))?;
+    batch.extracts.extend(prefetched);
+    // Pages missing from the batch response are downloaded on their own,
+    // all at the same time:
+    let mut errors: HashMap<String, Box<dyn std::error::Error>> = HashMap::new();
+    let mut redirects: HashMap<String, String> = HashMap::new();
+    let separate: Vec<&String> = batch.failed.iter().chain(&batch.skipped).collect();
+    if !separate.is_empty() && ids.len() > 1 {
+        if !batch.failed.is_empty() {
+            progress!(
+                config,
+                "{} of {} pages came without content in the batch response, downloading them separately.",
+                batch.failed.len(),
+                ids.len()
+            );
+        }
+        if !batch.skipped.is_empty() {
+            progress!(
+                config,
+                "Wikipedia left {} of {} pages out of the batch response, downloading them separately.",
+                batch.skipped.len(),
+                ids.len()
+            );
+        }
+        let singles = futures::future::join_all(
+            separate
+                .iter()
+                .map(|id| download_wikipedia_page(config, id)),
+        )
+        .await;
+        for (id, single) in separate.into_iter().zip(singles) {
+            match single {
+                Ok((text, redirect)) => {
+                    batch.extracts.insert(id.clone(), text);
+                    if let Some(title) = redirect {
+                        redirects.insert(id.clone(), title);
+                    }
+                }
+                Err(e) => {
+                    errors.insert(id.clone(), e);
+                }
+            }
+        }
+    }
+    let mut i = 0;
+    while i < config.pages_wanted() && i < r.pages.len() {
+        let id = r.pages[i].page_id.clone();
+        let page = match (batch.extracts.remove(&id), errors.remove(&id)) {
+            (Some(text), _) => Ok((text, redirects.remove(&id))),
+            (None, Some(e)) => Err(e),
+            (None, None) => download_wikipedia_page(config, &id).await,
+        };
+        let page = match page {
+            // Cited under the title of the article, unless that one is
+            // among the pages already:
+            Ok((_, Some(title))) if r.pages[..i].iter().any(|p| p.title == title) => {
+                if config.verbose {
+                    eprintln!(
+                        "Skipping '{}' because it redirects to '{}', which is used already.",
+                        r.pages[i].title, title
+                    );
+                }
+                r.pages.remove(i);
+                continue;
+            }
+            Ok((text, redirect)) => {
+                if let Some(title) = redirect {
+                    r.pages[i].title = title;
+                }
+                text
+            }
+            // Not an article, even a strict download goes on with the
+            // next search result:
+            Err(e) if e.is::<UnusablePage>() => {
+                if config.verbose {
+                    eprintln!(
+                        "Skipping '{}' because {}, using the next search result instead.",
+                        r.pages[i].title, e
+                    );
+                }
+                r.pages.remove(i);
+                continue;
+            }
+            Err(e) if config.strict_downloads => r.check(
+                config,
+                Err(WikiRagError::Download(
+                    format!("could not download '{}': {}", r.pages[i].title, e).into(),
+                )),
+            )?,
+            Err(e) => {
+                progress!(
+                    config,
+                    "Could not download '{}' ({}), using the next search result instead.",
+                    r.pages[i].title,
+                    e
+                );
+                r.pages.remove(i);
+                continue;
+            }
+        };
+        config.emit(PipelineEvent::PageDownloaded {
+            page_id: r.pages[i].page_id.clone(),
+            title: r.pages[i].title.clone(),
+            size: page.len(),
+        });
+        r.page_strings.push(page);
+
+        if let Some(budget) = config.max_total_download_bytes {
+            let total: usize = r.page_strings.iter().map(|p| p.len()).sum();
+            if total >= budget && i + 1 < config.pages_wanted() && i + 1 < r.pages.len() {
+                progress!(
+                    config,
+                    "Download budget of {} bytes reached ({} bytes in {} pages), not downloading further pages.",
+                    budget,
+                    total,
+                    r.page_strings.len()
+                );
+                break;
+            }
+        }
+        if config.adaptive_context
+            && i + 1 < config.pages_wanted()
+            && i + 1 < r.pages.len()
+            && can_answer(config, question, &r.page_strings).await
+        {
+            break;
+        }
+        i += 1;
+    }
+    if config.adaptive_context && !r.page_strings.is_empty() {
+        progress!(
+            config,
+            "Adaptive context: {} of up to {} pages were needed.",
+            r.page_strings.len(),
+            config.pages_wanted().min(r.pages.len())
+        );
+    }
+    if r.page_strings.is_empty() && !wanted.is_empty() {
+        r.check::<()>(
+            config,
+            Err(WikiRagError::Download(
+                "none of the found pages could be downloaded".into(),
+            )),
+        )?;
+    }
+
+    if config.best_page_only && config.verbose && !r.page_strings.is_empty() {
+        let why = if config.select == PageSelection::Llm {
+            "picked by the LLM from the candidates"
+        } else if config.prefer_quality {
+            "best search result after ranking by quality"
+        } else if config.rerank {
+            "most similar to the question after reranking"
+        } else {
+            "top search result"
+        };
+        eprintln!(
+            "Answering from the single page '{}' only: {} for '{}'.",
+            r.pages[0].title, why, r.keywords
+        );
+    }
+
+    if config.include_linked > 0 && !config.best_page_only && !r.page_strings.is_empty() {
+        add_linked_pages(config, question, &mut r).await;
+    }
+
+    if let Some(target) = config.compact_context {
+        compact_pages(config, question, &mut r, target).await;
+    }
+
+    if config.last_edited && !r.page_strings.is_empty() {
+        let used = r.page_strings.len();
+        let ids: Vec<&str> = r.pages[..used].iter().map(|p| p.page_id.as_str()).collect();
+        match fetch_last_edited(config, &ids).await {
+            Ok(stamps) => {
+                for p in r.pages[..used].iter_mut() {
+                    p.last_edited = stamps
+                        .iter()
+                        .find(|(id, _)| *id == p.page_id)
+                        .map(|(_, ts)| ts.clone());
+                    warn_about_edit_age(config, p);
+                }
+            }
+            Err(e) => progress!(config, "Could not fetch last edit dates: {}", e),
+        }
+    }
+
+    Ok(r)
+}
+
+/// Replaces the downloaded pages longer than `target` characters by
+/// summaries of about that length, focused on the question. The pages
+/// are summarized in parallel, a page whose summary fails is kept whole.
+async fn compact_pages(config: &Config, question: &str, r: &mut Retrieval, target: usize) {
+    let long: Vec<usize> = (0..r.page_strings.len())
+        .filter(|&i| r.page_strings[i].chars().count() > target)
+        .collect();
+    if long.is_empty() {
+        return;
+    }
+    progress!(
+        config,
+        "Summarizing {} pages to about {} characters each...",
+        long.len(),
+        target
+    );
+    let system = format!(
+        "Summarize the following Wikipedia text in at most {} characters. Keep the facts which are relevant to this question, with their names, dates and numbers: {}\nReply with the summary only.",
+        target, question
+    );
+    let cost_before = config.usage.cost();
+    // Roughly four characters per token, with room for the last sentence:
+    let max_tokens = (target / 4 + 64) as u32;
+    let summaries = futures::future::join_all(
+        long.iter()
+            .map(|&i| complete(config, &system, &r.page_strings[i], max_tokens)),
+    )
+    .await;
+    let (mut before, mut after) = (0, 0);
+    for (&i, summary) in long.iter().zip(summaries) {
+        let original = r.page_strings[i].len();
+        match summary {
+            Ok(summary) => {
+                progress!(
+                    config,
+                    "  '{}': {} -> {} characters",
+                    r.pages[i].title,
+                    original,
+                    summary.len()
+                );
+                before += original;
+                after += summary.len();
+                r.page_strings[i] = summary.trim().to_string();
+            }
+            Err(e) => progress!(
+                config,
+                "Could not summarize '{}', using the whole page: {}",
+                r.pages[i].title,
+                e
+            ),
+        }
+    }
+    progress!(
+        config,
+        "Compacted the context from {} to {} characters, the summaries cost ${:.6}.",
+        before,
+        after,
+        config.usage.cost() - cost_before
+    );
+}
+
+/// Downloads the most relevant pages linked from the first page as
+/// additional context. They are inserted right after the downloaded
+/// pages, so that `pages` and `page_strings` stay aligned.
+async fn add_linked_pages(config: &Config, question: &str, r: &mut Retrieval) {
+    let primary = r.pages[0].clone();
+    let linked = match fetch_linked_pages(config, &primary.page_id).await {
+        Ok(linked) => linked,
+        Err(e) => {
+            progress!(
+                config,
+                "Could not fetch links of '{}': {}",
+                primary.title,
+                e
+            );
+            return;
+        }
+    };
+    let candidates = rank_linked_pages(question, linked, &r.pages, config.include_linked as usize);
+    if candidates.is_empty() {
+        progress!(
+            config,
+            "None of the pages linked from '{}' look relevant to the question.",
+            primary.title
+        );
+    }
+    for (mut page, shared) in candidates {
+        if let Some(budget) = config.max_total_download_bytes {
+            let total: usize = r.page_strings.iter().map(|p| p.len()).sum();
+            if total >= budget {
+                progress!(config, "Download budget reached, not adding linked pages.");
+                break;
+            }
+        }
+        let text = match download_wikipedia_page(config, &page.page_id).await {
+            Ok((text, redirect)) => {
+                if let Some(title) = redirect {
+                    page.title = title;
+                }
+                text
+            }
+            Err(e) => {
+                progress!(
+                    config,
+                    "Could not download linked page '{}': {}",
+                    page.title,
+                    e
+                );
+                continue;
+            }
+        };
+        progress!(
+            config,
+            "Adding linked page '{}' from '{}', its title shares {} with the question.",
+            page.title,
+            primary.title,
+            shared.join(", ")
+        );
+        config.emit(PipelineEvent::PageDownloaded {
+            page_id: page.page_id.clone(),
+            title: page.title.clone(),
+            size: text.len(),
+        });
+        r.pages.insert(r.page_strings.len(), page);
+        r.page_strings.push(text);
+    }
+}
+
+async fn answer_with_provider(
+    config: &Config,
+    llm: &dyn LlmBackend,
+    page_strings: &[String],
+    history: &[Exchange],
+    question: &str,
+    stream: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut retries = 0;
+    loop {
+        let res = llm.answer(page_strings, history, question, stream).await;
+        match res {
+            Err(e) if e.is::<StreamInterrupted>() && retries < config.stream_retries => {
+                retries += 1;
+                progress!(
+                    config,
+                    "{}, retrying the answer ({} of {})...",
+                    e,
+                    retries,
+                    config.stream_retries
+                );
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Answers the question against every page on its own, in parallel, and
+/// then lets the LLM reconcile the partial answers into one.
+async fn answer_map_reduce(
+    config: &Config,
+    llm: &dyn LlmBackend,
+    retrieval: &Retrieval,
+    history: &[Exchange],
+    question: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Every page is answered on its own, so it may take the whole window:
+    let singles: Vec<Vec<String>> = single_context_pages(config, retrieval)
+        .into_iter()
+        .map(|p| vec![p])
+        .collect();
+    for page in &singles {
+        check_prompt_fits(config, page, history, question)?;
+    }
+    let partials = futures::future::join_all(
+        singles
+            .iter()
+            .map(|page| answer_with_provider(config, llm, page, history, question, false)),
+    )
+    .await;
+
+    let mut answers: Vec<String> = vec![];
+    for (page, partial) in retrieval.pages.iter().zip(partials) {
+        let partial = partial?;
+        if config.verbose {
+            eprintln!("Partial answer from '{}':\n{}\n", page.title, partial);
+        }
+        answers.push(format!(
+            "Answer based on the Wikipedia page '{}':\n{}",
+            page.title, partial
+        ));
+    }
+
+    progress!(config, "Combining {} partial answers...", answers.len());
+    let reduce_question = format!(
+        "{}\n\nThe provided texts are answers to this question, each based on a single Wikipedia page. Reconcile them into one answer, resolve contradictions and name the pages you used as sources.",
+        question
+    );
+    check_prompt_fits(config, &answers, history, &reduce_question)?;
+    answer_with_provider(
+        config,
+        llm,
+        &answers,
+        history,
+        &reduce_question,
+        config.streams(),
+    )
+    .await
+}
+
+/// Shows the answer request as it would be sent to the provider, and
+/// about how many tokens it takes, instead of sending it.
+pub fn dry_run(
+    config: &Config,
+    retrieval: &Retrieval,
+    history: &[Exchange],
+    question: &str,
+) -> Result<(), WikiRagError> {
+    check_question(question)?;
+    let pages = context_pages(config, retrieval);
+    let res = llm::backend(config).answer_request(&pages, history, question);
+    let request = retrieval.check(config, res.map_err(WikiRagError::llm))?;
+    println!("{}", serde_json::to_string_pretty(&request).unwrap());
+    // On stderr, so that stdout holds just the JSON request:
+    progress!(
+        config,
+        "Approximate prompt tokens: {} of the {} tokens of model {}, plus up to {} for the answer.",
+        estimate_prompt_tokens(config, &pages, history, question),
+        context_window(config),
+        config.model,
+        config.answer_max_tokens
+    );
+    Ok(())
+}
+
+/// Answers the question from the retrieved pages. A failure is returned,
+/// so that the pages can still be offered, see [`print_unanswered`].
+pub async fn answer_question(
+    config: &Config,
+    retrieval: &Retrieval,
+    history: &[Exchange],
+    question: &str,
+) -> Result<String, WikiRagError> {
+    check_question(question)?;
+    progress!(
+        config,
+        "\nAnswering question using Wikipedia pages and LLM model..."
+    );
+    if config.verbose && !config.source_trust.is_empty() {
+        let mut ordered: Vec<&WikiPage> = retrieval.pages[..retrieval.page_strings.len()]
+            .iter()
+            .collect();
+        ordered.sort_by_key(|p| std::cmp::Reverse(trust_level(config, p)));
+        eprintln!("Sources by trust:");
+        for p in ordered {
+            eprintln!("  {} (trust {})", p.title, trust_level(config, p));
+        }
+    }
+    let llm = llm::backend(config);
+    let res = if config.answer_mode == AnswerMode::MapReduce && retrieval.page_strings.len() > 1 {
+        answer_map_reduce(config, llm.as_ref(), retrieval, history, question).await
+    } else {
+        let pages = context_pages(config, retrieval);
+        check_prompt_fits(config, &pages, history, question).map_err(WikiRagError::llm)?;
+        answer_with_provider(
+            config,
+            llm.as_ref(),
+            &pages,
+            history,
+            question,
+            config.streams(),
+        )
+        .await
+    };
+    let mut answer = res.map_err(WikiRagError::llm)?;
+    if let Some(schema) = &config.answer_schema {
+        let res = conform_to_schema(
+            config,
+            llm.as_ref(),
+            retrieval,
+            history,
+            question,
+            schema,
+            answer,
+        )
+        .await;
+        answer = res.map_err(WikiRagError::llm)?;
+    }
+    // Shortening would break a JSON answer.
+    if let Some(limit) = config
+        .answer_max_words
+        .filter(|_| config.answer_schema.is_none())
+    {
+        let long = answer.clone();
+        answer = enforce_word_limit(config, answer, limit).await;
+        if config.streams() && answer != long {
+            config.emit(PipelineEvent::AnswerChunk {
+                text: format!("\n\nShortened answer:\n{}", answer),
+            });
+        }
+    }
+    // A streamed answer has already been delivered in pieces.
+    if !config.streams() {
+        config.emit(PipelineEvent::AnswerChunk {
+            text: answer.clone(),
+        });
+    }
+    if config.extractive {
+        verify_quotes(config, retrieval, &answer);
+    }
+    let translations = if config.answer_langs.is_empty() {
+        BTreeMap::new()
+    } else {
+        translate_answer(config, &answer).await
+    };
+    let evidence = if config.explain {
+        find_evidence(retrieval, &answer)
+    } else {
+        vec![]
+    };
+    let confidence = if config.with_confidence {
+        assess_confidence(config, retrieval, question, &answer).await
+    } else {
+        None
+    };
+    config.emit(PipelineEvent::Completed(AnswerReport {
+        question: question.to_string(),
+        keywords: retrieval.keywords.clone(),
+        pages: retrieval.pages[..retrieval.page_strings.len()].to_vec(),
+        answer: answer.clone(),
+        evidence,
+        confidence,
+        translations,
+    }));
+    Ok(answer)
+}
+
+/// Prints the keywords and the pages found when the question could not
+/// be answered, so that the user can read the pages themselves.
+pub fn print_unanswered(
+    config: &Config,
+    retrieval: &Retrieval,
+    question: &str,
+    error: &WikiRagError,
+) {
+    let pages = &retrieval.pages[..retrieval.page_strings.len()];
+    match config.format {
+        OutputFormat::Text => {
+            println!(
+                "No answer, but these pages were found for '{}':",
+                retrieval.keywords
+            );
+            for p in pages {
+                println!("{}", wiki_url(&config.language, &p.title));
+            }
+        }
+        OutputFormat::Markdown => {
+            println!(
+                "No answer, but these pages were found for *{}*:\n",
+                retrieval.keywords
+            );
+            for p in pages {
+                println!("- [{}]({})", p.title, wiki_url(&config.language, &p.title));
+            }
+        }
+        OutputFormat::Json => {
+            let pages: Vec<_> = pages
+                .iter()
+                .map(|p| {
+                    serde_json::json!({
+                        "page_id": p.page_id,
+                        "title": p.title,
+                        "url": wiki_url(&config.language, &p.title),
+                    })
+                })
+                .collect();
+            let json = serde_json::json!({
+                "question": question,
+                "keywords": retrieval.keywords,
+                "pages": pages,
+                "error": error.to_string(),
+            });
+            println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        }
+    }
+}
+
+/// Translates the answer into every language of `ANSWER_LANGS`, in
+/// parallel. Failed translations are reported and left out.
+async fn translate_answer(config: &Config, answer: &str) -> BTreeMap<String, String> {
+    progress!(
+        config,
+        "Translating the answer into {}...",
+        config.answer_langs.join(", ")
+    );
+    let before = (
+        config.usage.prompt_tokens(),
+        config.usage.completion_tokens(),
+        config.usage.cost(),
+    );
+    let prompts: Vec<String> = config
+        .answer_langs
+        .iter()
+        .map(|code| {
+            format!(
+                "Translate the following answer into {}. Keep names, numbers, citations and the formatting. Reply with the translation only.",
+                lang::language_name(code)
+            )
+        })
+        .collect();
+    // Other scripts can take more tokens than the original:
+    let results = futures::future::join_all(
+        prompts
+            .iter()
+            .map(|system| complete(config, system, answer, config.answer_max_tokens * 2)),
+    )
+    .await;
+    let mut translations = BTreeMap::new();
+    for (code, result) in config.answer_langs.iter().zip(results) {
+        match result {
+            Ok(text) => {
+                translations.insert(code.clone(), text.trim().to_string());
+            }
+            Err(e) => progress!(
+                config,
+                "Could not translate the answer into {}: {}",
+                code,
+                e
+            ),
+        }
+    }
+    progress!(
+        config,
+        "Translations: tokens in: {}, tokens out: {}, cost ${:.6}",
+        config.usage.prompt_tokens() - before.0,
+        config.usage.completion_tokens() - before.1,
+        config.usage.cost() - before.2
+    );
+    translations
+}
+
+/// The JSON document in an answer, without the code fence models like
+/// to put around it.
+fn json_part(answer: &str) -> &str {
+    let answer = answer.trim();
+    answer
+        .strip_prefix("```json")
+        .or_else(|| answer.strip_prefix("```"))
+        .and_then(|a| a.strip_suffix("```"))
+        .unwrap_or(answer)
+        .trim()
+}
+
+/// Everything in which the answer does not conform to the schema, empty
+/// if it does.
+fn schema_violations(schema: &serde_json::Value, answer: &str) -> Vec<String> {
+    let document: serde_json::Value = match serde_json::from_str(json_part(answer)) {
+        Ok(document) => document,
+        Err(e) => return vec![format!("the answer is not valid JSON: {}", e)],
+    };
+    // The schema was checked when it was loaded.
+    let validator = jsonschema::validator_for(schema).unwrap();
+    validator
+        .iter_errors(&document)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            if path.is_empty() {
+                e.to_string()
+            } else {
+                format!("{} (at {})", e, path)
+            }
+        })
+        .collect()
+}
+
+/// Validates an answer against the answer schema. If it does not conform,
+/// the LLM is asked once more, with the problems fed back to it.
+async fn conform_to_schema(
+    config: &Config,
+    llm: &dyn LlmBackend,
+    retrieval: &Retrieval,
+    history: &[Exchange],
+    question: &str,
+    schema: &serde_json::Value,
+    answer: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let violations = schema_violations(schema, &answer);
+    if violations.is_empty() {
+        return Ok(json_part(&answer).to_string());
+    }
+    progress!(
+        config,
+        "The answer does not conform to the schema, asking again:\n  {}",
+        violations.join("\n  ")
+    );
+    let corrected_question = format!(
+        "{}\n\nYour previous answer was:\n{}\n\nIt does not conform to the JSON schema: {}. Reply with a corrected JSON document only.",
+        question,
+        answer,
+        violations.join("; ")
+    );
+    let pages = context_pages(config, retrieval);
+    let answer =
+        answer_with_provider(config, llm, &pages, history, &corrected_question, false).await?;
+    let violations = schema_violations(schema, &answer);
+    if violations.is_empty() {
+        Ok(json_part(&answer).to_string())
+    } else {
+        Err(format!(
+            "The answer does not conform to the answer schema: {}",
+            violations.join("; ")
+        )
+        .into())
+    }
+}
+
+/// The score in the reply of the LLM.
+static DECIMAL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+(?:\.\d+)?").unwrap());
+
+/// Asks the LLM how well the pages support the answer, as a score from 0
+/// to 1. Failures are reported and give no score.
+async fn assess_confidence(
+    config: &Config,
+    retrieval: &Retrieval,
+    question: &str,
+    answer: &str,
+) -> Option<f64> {
+    progress!(config, "Assessing the confidence of the answer...");
+    let system = "You are given texts from Wikipedia, a question and an answer to it. Rate how well the texts support the answer with a number between 0 and 1, where 1 means every statement of the answer is backed by the texts and 0 means none is. Reply with the number only.";
+    let user = format!(
+        "{}\n\nQuestion: {}\n\nAnswer: {}",
+        context_pages(config, retrieval).join("\n"),
+        question,
+        answer
+    );
+    let reply = match complete(config, system, &user, 8).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            progress!(config, "Could not assess the confidence: {}", e);
+            return None;
+        }
+    };
+    match DECIMAL
+        .find(&reply)
+        .and_then(|m| m.as_str().parse::<f64>().ok())
+    {
+        Some(score) => Some(score.clamp(0.0, 1.0)),
+        None => {
+            progress!(
+                config,
+                "The confidence reply '{}' has no score.",
+                reply.trim()
+            );
+            None
+        }
+    }
+}
+
+/// Asks the LLM to compress an answer which is longer than `limit` words.
+/// If that fails, the original answer is kept.
+async fn enforce_word_limit(config: &Config, answer: String, limit: usize) -> String {
+    let words = answer.split_whitespace().count();
+    if words <= limit {
+        return answer;
+    }
+    let system = format!(
+        "Shorten the following answer to at most {} words. Keep the facts which answer the question and keep every citation and source reference. Reply with the shortened answer only.",
+        limit
+    );
+    match complete(config, &system, &answer, config.answer_max_tokens).await {
+        Ok(short) => {
+            let short = short.trim().to_string();
+            let short_words = short.split_whitespace().count();
+            progress!(
+                config,
+                "Answer compressed from {} to {} words (limit {}).",
+                words,
+                short_words,
+                limit
+            );
+            if short_words > limit {
+                eprintln!(
+                    "Warning: the compressed answer still exceeds the limit of {} words.",
+                    limit
+                );
+            }
+            short
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: could not compress the answer of {} words: {}",
+                words, e
+            );
+            answer
+        }
+    }
+}
+
+/// The page texts as given to the LLM: windowed around the keywords if
+/// configured, and in extractive mode every page is headed by its title,
+/// so that quotes can name their source. With trust weights the header
+/// also carries the trust level.
+fn context_pages(config: &Config, retrieval: &Retrieval) -> Vec<String> {
+    let texts = truncate_to_budget(
+        config,
+        &retrieval.pages,
+        &retrieval.keywords,
+        windowed_texts(config, retrieval),
+        max_context_chars(config),
+    );
+    headed_pages(config, retrieval, texts)
+}
+
+/// The page texts like [`context_pages`], but each trimmed to the whole
+/// context window on its own, for answering every page separately.
+fn single_context_pages(config: &Config, retrieval: &Retrieval) -> Vec<String> {
+    let texts = windowed_texts(config, retrieval)
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let page = retrieval.pages.get(i..=i).unwrap_or_default();
+            truncate_to_budget(
+                config,
+                page,
+                &retrieval.keywords,
+                vec![text],
+                max_context_chars(config),
+            )
+            .remove(0)
+        })
+        .collect();
+    headed_pages(config, retrieval, texts)
+}
+
+/// The page texts, windowed around the keywords if configured.
+fn windowed_texts(config: &Config, retrieval: &Retrieval) -> Vec<String> {
+    match config.context_window_chars {
+        Some(n) => retrieval
+            .page_strings
+            .iter()
+            .map(|text| keyword_windows(text, &retrieval.keywords, n))
+            .collect(),
+        None => retrieval.page_strings.clone(),
+    }
+}
+
+/// Puts the title, and the trust level if configured, above every text.
+fn headed_pages(config: &Config, retrieval: &Retrieval, texts: Vec<String>) -> Vec<String> {
+    if !config.source_trust.is_empty() {
+        return retrieval
+            .pages
+            .iter()
+            .zip(texts.iter())
+            .map(|(p, text)| {
+                format!(
+                    "Title: {}\nTrust level: {}\n\n{}",
+                    p.title,
+                    trust_level(config, p),
+                    text
+                )
+            })
+            .collect();
+    }
+    if !config.extractive {
+        return texts;
+    }
+    retrieval
+        .pages
+        .iter()
+        .zip(texts.iter())
+        .map(|(p, text)| format!("Title: {}\n\n{}", p.title, text))
+        .collect()
+}
+
+/// Room in the prompt for the question, the instructions and the history
+/// of a conversation, in tokens.
+const PROMPT_RESERVE_TOKENS: u32 = 1000;
+
+/// How many characters of page text fit into the context window of the
+/// model, next to the answer and the rest of the prompt.
+fn max_context_chars(config: &Config) -> usize {
+    let tokens =
+        context_window(config).saturating_sub(config.answer_max_tokens + PROMPT_RESERVE_TOKENS);
+    // The inverse of `ratelimit::estimate_tokens`:
+    tokens as usize * 4
+}
+
+/// Trims the page texts to fit into `budget` characters together. Every
+/// page gets an equal share, and what short pages leave of theirs goes to
+/// the longer ones. Pages are cut at a paragraph boundary, or with
+/// `SECTIONS` by dropping their least relevant sections.
+fn truncate_to_budget(
+    config: &Config,
+    pages: &[WikiPage],
+    keywords: &str,
+    texts: Vec<String>,
+    budget: usize,
+) -> Vec<String> {
+    let total: usize = texts.iter().map(|t| t.len()).sum();
+    if total <= budget {
+        return texts;
+    }
+    let mut order: Vec<usize> = (0..texts.len()).collect();
+    order.sort_by_key(|&i| texts[i].len());
+    let mut shares = vec![0; texts.len()];
+    let mut remaining = budget;
+    for (done, &i) in order.iter().enumerate() {
+        let share = (remaining / (texts.len() - done)).min(texts[i].len());
+        shares[i] = share;
+        remaining -= share;
+    }
+    texts
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| {
+            if text.len() <= shares[i] {
+                return text;
+            }
+            let cut = if config.section_aware {
+                extract::WikiExtract::parse(&text).fit(shares[i], keywords)
+            } else {
+                cut_at_paragraph(&text, shares[i]).to_string()
+            };
+            if config.verbose {
+                eprintln!(
+                    "Truncated '{}' to {} characters to fit the context window, dropped {}.",
+                    pages.get(i).map(|p| p.title.as_str()).unwrap_or("?"),
+                    cut.len(),
+                    text.len() - cut.len()
+                );
+            }
+            cut
+        })
+        .collect()
+}
+
+/// The longest start of `text` of at most `max` bytes which ends at a
+/// paragraph boundary, or failing that at a line or sentence end.
+fn cut_at_paragraph(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let head = &text[..end];
+    // Only give up much of the share for a clean cut if there is one:
+    for boundary in ["\n\n", "\n", ". "] {
+        if let Some(pos) = head.rfind(boundary) {
+            if pos >= end / 2 {
+                return &text[..pos + boundary.trim_end().len()];
+            }
+        }
+    }
+    head
+}
+
+/// The trust level of a page: the weight of the first matching pattern
+/// of `SOURCE_TRUST`, or 1.
+fn trust_level(config: &Config, page: &WikiPage) -> u32 {
+    config
+        .source_trust
+        .iter()
+        .find(|(re, _)| re.is_match(&page.title))
+        .map(|(_, weight)| *weight)
+        .unwrap_or(1)
+}
+
+/// Shortens an extract to its intro plus windows of about `n` characters
+/// around every occurrence of a keyword word, joined by ellipses.
+/// Overlapping windows are merged. Texts which would not get shorter are
+/// returned unchanged.
+fn keyword_windows(text: &str, keywords: &str, n: usize) -> String {
+    // The intro is everything before the first section heading:
+    let intro_end = text.find("\n== ").unwrap_or(text.len());
+    let words = significant_words(keywords);
+    if words.is_empty() || intro_end == text.len() {
+        return text.to_string();
+    }
+    let pattern = words
+        .iter()
+        .map(|w| regex::escape(w))
+        .collect::<Vec<_>>()
+        .join("|");
+    let re = Regex::new(&format!("(?i){}", pattern)).unwrap();
+
+    let mut windows: Vec<(usize, usize)> = vec![];
+    for m in re.find_iter(&text[intro_end..]) {
+        let mut start = (intro_end + m.start()).saturating_sub(n / 2).max(intro_end);
+        let mut end = (intro_end + m.end() + n / 2).min(text.len());
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        match windows.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => windows.push((start, end)),
+        }
+    }
+
+    let mut result = text[..intro_end].trim_end().to_string();
+    for (start, end) in windows.iter() {
+        result.push_str("\n\n...");
+        result.push_str(text[*start..*end].trim());
+        result.push_str("...");
+    }
+    if result.len() < text.len() {
+        result
+    } else {
+        text.to_string()
+    }
+}
+
+/// A finished answer together with everything shown alongside it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnswerReport {
+    pub question: String,
+    pub keywords: String,
+    /// The pages the answer was given from.
+    pub pages: Vec<WikiPage>,
+    pub answer: String,
+    /// Supporting passages for the claims of the answer, if asked for
+    /// with `--explain`.
+    pub evidence: Vec<Evidence>,
+    /// How well the pages support the answer, from 0 to 1, if asked for
+    /// with `WITH_CONFIDENCE`.
+    pub confidence: Option<f64>,
+    /// The answer in the languages of `ANSWER_LANGS`, by language code.
+    pub translations: BTreeMap<String, String>,
+}
+
+/// A claim of the answer with the source passage supporting it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Evidence {
+    pub claim: String,
+    pub title: String,
+    pub passage: String,
+}
+
+/// Splits a text into sentences, roughly: at `.`, `!` and `?` followed by
+/// whitespace, and at line breaks.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end_of_sentence = match c {
+            '.' | '!' | '?' => match chars.peek() {
+                Some((_, n)) => n.is_whitespace(),
+                None => true,
+            },
+            '\n' => true,
+            _ => false,
+        };
+        if end_of_sentence {
+            let s = text[start..i + c.len_utf8()].trim();
+            if !s.is_empty() {
+                result.push(s);
+            }
+            start = i + c.len_utf8();
+        }
+    }
+    let rest = text[start..].trim();
+    if !rest.is_empty() {
+        result.push(rest);
+    }
+    result
+}
+
+/// A claim needs this share of its words in a source sentence to count
+/// as supported by it.
+const EVIDENCE_MIN_OVERLAP: f64 = 0.5;
+
+/// Finds, for every sentence of the answer, the source sentence sharing
+/// the most words with it. A verbatim occurrence is the best match;
+/// claims without a good enough match are left out.
+fn find_evidence(retrieval: &Retrieval, answer: &str) -> Vec<Evidence> {
+    // Every source sentence with its page and words:
+    let mut source_sentences: Vec<(&WikiPage, &str, Vec<String>)> = vec![];
+    for (page, text) in retrieval.pages.iter().zip(retrieval.page_strings.iter()) {
+        for sentence in sentences(text) {
+            source_sentences.push((page, sentence, significant_words(sentence)));
+        }
+    }
+
+    let mut evidence = vec![];
+    for claim in sentences(answer) {
+        let claim_words = significant_words(claim);
+        if claim_words.len() < 3 {
+            continue;
+        }
+        let normalized = normalize_whitespace(claim);
+        let mut best: Option<(f64, &WikiPage, &str)> = None;
+        for (page, sentence, words) in source_sentences.iter() {
+            let score = if normalize_whitespace(sentence).contains(&normalized) {
+                1.0
+            } else {
+                let shared = claim_words.iter().filter(|w| words.contains(w)).count();
+                shared as f64 / claim_words.len() as f64
+            };
+            if !matches!(best, Some((b, _, _)) if score <= b) {
+                best = Some((score, page, sentence));
+            }
+        }
+        if let Some((score, page, sentence)) = best {
+            if score >= EVIDENCE_MIN_OVERLAP {
+                evidence.push(Evidence {
+                    claim: claim.to_string(),
+                    title: page.title.clone(),
+                    passage: sentence.to_string(),
+                });
+            }
+        }
+    }
+    evidence
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A quote in double quotes, straight or typographic.
+static QUOTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"["“]([^"”]+)["”]"#).unwrap());
+
+/// Checks that every quote in an extractive answer actually appears in
+/// one of the source pages, and warns about those which do not.
+fn verify_quotes(config: &Config, retrieval: &Retrieval, answer: &str) {
+    let sources: Vec<String> = retrieval
+        .page_strings
+        .iter()
+        .map(|p| normalize_whitespace(p))
+        .collect();
+    let mut fabricated = 0;
+    for cap in QUOTE.captures_iter(answer) {
+        let q = normalize_whitespace(&cap[1]);
+        if !sources.iter().any(|s| s.contains(&q)) {
+            fabricated += 1;
+            eprintln!("Warning: quote not found in the sources: \"{}\"", q);
+        }
+    }
+    if fabricated == 0 {
+        progress!(config, "All quotes were found verbatim in the sources.");
+    }
+}
@@ -0,0 +1,955 @@
+//! The LLM providers behind one interface. Adding a provider means
+//! implementing [`LlmBackend`] for it and returning it from [`backend`].
+
+use crate::audit;
+use crate::error::WikiRagError;
+use crate::events::PipelineEvent;
+use crate::retry::{retry, HttpStatusError};
+use crate::{
+    conversation_turns, count_llm_call, extract_answer, instructed_question, keyword_max_tokens,
+    keyword_prompt, learn_keyword_tokens, pretty_print_usage, print_token_usage, progress,
+    ratelimit, record_usage, retained_history, separate_pages, stream_interrupted, Config,
+    Exchange, LlmProvider, OLLAMA_URL,
+};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionStreamOptions, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    CreateEmbeddingRequestArgs, FinishReason, ResponseFormat, ResponseFormatJsonSchema,
+};
+use async_openai::{config::OpenAIConfig, Client};
+use async_trait::async_trait;
+use futures::StreamExt;
+use ollama_rs::{
+    generation::chat::{request::ChatMessageRequest, ChatMessage},
+    generation::options::GenerationOptions,
+    generation::parameters::FormatType,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[async_trait(?Send)]
+pub trait LlmBackend {
+    /// Asks for the search keywords for the question.
+    async fn extract_keywords(&self, question: &str) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// Answers the question from the pages, continuing the conversation
+    /// in `history`. With `stream`, the answer is emitted in pieces as
+    /// it arrives.
+    async fn answer(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+        stream: bool,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// The request `answer` would send, in the format of the provider,
+    /// for `--dry-run`.
+    fn answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>>;
+
+    /// A deterministic call with a system instruction and a user message,
+    /// for the small helper steps around retrieval.
+    async fn complete(
+        &self,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// Embeds the texts with the provider's embedding model, or the one
+    /// set with `EMBEDDING_MODEL`.
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error>>;
+}
+
+/// The backend serving the configured model.
+pub fn backend(config: &Config) -> Box<dyn LlmBackend + '_> {
+    backend_of(config, config.llm_server)
+}
+
+/// The backend computing embeddings: the one of `EMBEDDING_PROVIDER`,
+/// or the one serving the configured model.
+pub fn embedder(config: &Config) -> Box<dyn LlmBackend + '_> {
+    backend_of(
+        config,
+        config.embedding_provider.unwrap_or(config.llm_server),
+    )
+}
+
+fn backend_of(config: &Config, provider: LlmProvider) -> Box<dyn LlmBackend + '_> {
+    match provider {
+        LlmProvider::OpenAI => Box::new(OpenAiBackend { config }),
+        LlmProvider::Ollama => Box::new(OllamaBackend { config }),
+        LlmProvider::Anthropic => Box::new(AnthropicBackend { config }),
+    }
+}
+
+pub struct OpenAiBackend<'a> {
+    config: &'a Config,
+}
+
+pub struct OllamaBackend<'a> {
+    config: &'a Config,
+}
+
+pub struct AnthropicBackend<'a> {
+    config: &'a Config,
+}
+
+/// How often the keyword request is repeated with a doubled token limit
+/// when the response was cut off.
+const KEYWORD_LENGTH_RETRIES: u32 = 2;
+
+impl OpenAiBackend<'_> {
+    fn build_answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Result<CreateChatCompletionRequest, Box<dyn std::error::Error>> {
+        let config = self.config;
+        let mut messages: Vec<ChatCompletionRequestMessage> = vec![];
+        for w in separate_pages(config, pages).iter() {
+            messages.push(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(w.as_str())
+                    .name("Wikipedia".to_string())
+                    .build()?
+                    .into(),
+            );
+        }
+        for (i, (q, a)) in conversation_turns(history, question).enumerate() {
+            let content = if i == 0 {
+                instructed_question(config, pages, q)
+            } else {
+                q.to_string()
+            };
+            messages.push(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(content)
+                    .build()?
+                    .into(),
+            );
+            if let Some(a) = a {
+                messages.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(a)
+                        .build()?
+                        .into(),
+                );
+            }
+        }
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.max_tokens(config.answer_max_tokens)
+            .model(&config.model)
+            .messages(messages);
+        if let Some(t) = config.answer_temperature {
+            args.temperature(t);
+        }
+        if let Some(schema) = &config.answer_schema {
+            args.response_format(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name: "answer".into(),
+                    schema: Some(schema.clone()),
+                    strict: None,
+                },
+            });
+        }
+        Ok(args.build()?)
+    }
+}
+
+/// An OpenAI client sending its requests through the configured HTTP
+/// client, so that they share its timeout.
+pub(crate) fn openai_client(config: &Config) -> Client<OpenAIConfig> {
+    Client::with_config(OpenAIConfig::default()).with_http_client(config.http.clone())
+}
+
+#[async_trait(?Send)]
+impl LlmBackend for OpenAiBackend<'_> {
+    async fn extract_keywords(&self, question: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        let client = openai_client(config);
+        let mut max_tokens = keyword_max_tokens(config);
+        let mut retries = 0;
+        loop {
+            count_llm_call(config)?;
+            let request = CreateChatCompletionRequestArgs::default()
+                .max_tokens(max_tokens)
+                .temperature(config.keyword_temperature)
+                .model(&config.model)
+                .messages([
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(keyword_prompt(config, question))
+                        .build()?
+                        .into(),
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(question)
+                        .build()?
+                        .into(),
+                ])
+                .build()?;
+
+            config
+                .rate_limiter
+                .acquire(ratelimit::estimate_tokens(&serde_json::to_string(&request)?) + max_tokens)
+                .await;
+            let response = {
+                let (client, request) = (&client, &request);
+                retry(config, "OpenAI request", move || async move {
+                    Ok(client.chat().create(request.clone()).await?)
+                })
+                .await?
+            };
+            audit::record(config, "openai-chat", &request, &response);
+
+            pretty_print_usage(config, response.usage.clone());
+
+            let cut_off = response
+                .choices
+                .first()
+                .is_some_and(|c| c.finish_reason == Some(FinishReason::Length));
+            if cut_off && retries < KEYWORD_LENGTH_RETRIES {
+                retries += 1;
+                progress!(
+                    config,
+                    "Keyword response was cut off at {} tokens, retrying with {}.",
+                    max_tokens,
+                    max_tokens * 2
+                );
+                max_tokens *= 2;
+                learn_keyword_tokens(&config.model, max_tokens);
+                continue;
+            }
+            return extract_answer(&response, 0);
+        }
+    }
+
+    async fn answer(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+        stream: bool,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let client = openai_client(config);
+        let request = self.build_answer_request(pages, history, question)?;
+
+        config
+            .rate_limiter
+            .acquire(
+                ratelimit::estimate_tokens(&serde_json::to_string(&request)?)
+                    + config.answer_max_tokens,
+            )
+            .await;
+        if stream {
+            // The usage comes in an extra chunk at the end:
+            let mut request = request.clone();
+            request.stream_options = Some(ChatCompletionStreamOptions {
+                include_usage: true,
+            });
+            let mut chunks = client.chat().create_stream(request.clone()).await?;
+            let mut answer = String::new();
+            let mut usage = None;
+            while let Some(chunk) = chunks.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if chunk.usage.is_some() {
+                            usage = chunk.usage.clone();
+                        }
+                        if let Some(text) =
+                            chunk.choices.first().and_then(|c| c.delta.content.clone())
+                        {
+                            config.emit(PipelineEvent::AnswerChunk { text: text.clone() });
+                            answer.push_str(&text);
+                        }
+                    }
+                    Err(e) => return Err(stream_interrupted(config, answer, e.to_string())),
+                }
+            }
+            audit::record(
+                config,
+                "openai-chat-stream",
+                &request,
+                &json!({ "text": answer, "usage": usage }),
+            );
+            pretty_print_usage(config, usage);
+            return Ok(answer);
+        }
+        let response = {
+            let (client, request) = (&client, &request);
+            retry(config, "OpenAI request", move || async move {
+                Ok(client.chat().create(request.clone()).await?)
+            })
+            .await?
+        };
+        audit::record(config, "openai-chat", &request, &response);
+
+        pretty_print_usage(config, response.usage.clone());
+
+        extract_answer(&response, 0)
+    }
+
+    fn answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        Ok(serde_json::to_value(
+            self.build_answer_request(pages, history, question)?,
+        )?)
+    }
+
+    async fn complete(
+        &self,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let client = openai_client(config);
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .max_tokens(max_tokens)
+            .temperature(0.0)
+            .model(&config.model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(system)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(user)
+                    .build()?
+                    .into(),
+            ])
+            .build()?;
+
+        config
+            .rate_limiter
+            .acquire(ratelimit::estimate_tokens(&serde_json::to_string(&request)?) + max_tokens)
+            .await;
+        let response = {
+            let (client, request) = (&client, &request);
+            retry(config, "OpenAI request", move || async move {
+                Ok(client.chat().create(request.clone()).await?)
+            })
+            .await?
+        };
+        audit::record(config, "openai-chat", &request, &response);
+
+        pretty_print_usage(config, response.usage.clone());
+
+        extract_answer(&response, 0)
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let model = config
+            .embedding_model
+            .clone()
+            .unwrap_or_else(|| "text-embedding-3-small".into());
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(model)
+            .input(texts)
+            .build()?;
+        let client = openai_client(config);
+        let mut response = {
+            let (client, request) = (&client, &request);
+            retry(config, "OpenAI embeddings request", move || async move {
+                Ok(client.embeddings().create(request.clone()).await?)
+            })
+            .await?
+        };
+        audit::record(config, "openai-embeddings", &request, &response);
+        response.data.sort_by_key(|e| e.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|e| e.embedding.into_iter().map(|x| x as f64).collect())
+            .collect())
+    }
+}
+
+/// The error of an Ollama response with a failure status.
+fn ollama_status_error(status: reqwest::StatusCode, body: String) -> Box<dyn std::error::Error> {
+    // Errors come as {"error": "..."}.
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v["error"].as_str().map(String::from))
+        .unwrap_or(body);
+    HttpStatusError {
+        status: status.as_u16(),
+        message: format!("Ollama request failed: {}", message),
+    }
+    .into()
+}
+
+/// Sends a chat request to Ollama without streaming and returns the raw
+/// response. ollama-rs drops its `error` and `done_reason` fields, which
+/// tell why a reply is missing.
+async fn ollama_chat(
+    config: &Config,
+    request: &ChatMessageRequest,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let mut body = serde_json::to_value(request)?;
+    body["stream"] = json!(false);
+    let url = format!("{}/api/chat", OLLAMA_URL);
+    let response: serde_json::Value = {
+        let (body, url) = (&body, &url);
+        retry(config, "Ollama request", move || async move {
+            let response = config.http.post(url).json(body).send().await?;
+            let status = response.status();
+            let text = response.text().await?;
+            if !status.is_success() {
+                return Err(ollama_status_error(status, text));
+            }
+            Ok(serde_json::from_str(&text)?)
+        })
+        .await?
+    };
+    audit::record(
+        config,
+        "ollama-chat",
+        &ollama_request_json(request),
+        &response,
+    );
+    Ok(response)
+}
+
+/// Sends a chat request to Ollama and emits the reply as it arrives, as
+/// one JSON object per line.
+async fn ollama_stream(
+    config: &Config,
+    request: &ChatMessageRequest,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut body = serde_json::to_value(request)?;
+    body["stream"] = json!(true);
+    let mut response = config
+        .http
+        .post(format!("{}/api/chat", OLLAMA_URL))
+        .json(&body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ollama_status_error(status, response.text().await?));
+    }
+    let mut answer = String::new();
+    let mut pending: Vec<u8> = vec![];
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => return Err(stream_interrupted(config, answer, e.to_string())),
+        };
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let part: serde_json::Value = match serde_json::from_slice(&line) {
+                Ok(part) => part,
+                Err(e) => return Err(stream_interrupted(config, answer, e.to_string())),
+            };
+            if let Some(error) = part["error"].as_str() {
+                return Err(stream_interrupted(config, answer, error.to_string()));
+            }
+            if let Some(text) = part["message"]["content"].as_str() {
+                config.emit(PipelineEvent::AnswerChunk {
+                    text: text.to_string(),
+                });
+                answer.push_str(text);
+            }
+            if let (Some(prompt_tokens), Some(completion_tokens)) = (
+                part["prompt_eval_count"].as_u64(),
+                part["eval_count"].as_u64(),
+            ) {
+                print_token_usage(config, prompt_tokens, completion_tokens);
+            }
+        }
+    }
+    audit::record(
+        config,
+        "ollama-chat-stream",
+        &ollama_request_json(request),
+        &json!({ "text": answer }),
+    );
+    Ok(answer)
+}
+
+/// Takes the reply text out of a raw Ollama chat response. An error in
+/// the response, or a missing or empty message, is a
+/// [`WikiRagError::NoReply`] with the reason Ollama gives.
+pub(crate) fn ollama_reply(
+    config: &Config,
+    response: &serde_json::Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let model = response["model"]
+        .as_str()
+        .unwrap_or(&config.model)
+        .to_string();
+    if let Some(error) = response["error"].as_str() {
+        return Err(WikiRagError::NoReply {
+            model,
+            reason: error.to_string(),
+        }
+        .into());
+    }
+    let generated = response["eval_count"].as_u64().unwrap_or(0);
+    if let Some(prompt_tokens) = response["prompt_eval_count"].as_u64() {
+        record_usage(config, prompt_tokens, generated);
+    }
+    let content = response["message"]["content"].as_str().unwrap_or("");
+    if !content.trim().is_empty() {
+        return Ok(content.to_string());
+    }
+    let reason = match response["done_reason"].as_str() {
+        Some("length") => format!(
+            "the token limit was reached after {} tokens without any text",
+            generated
+        ),
+        Some("load") => "the model was loaded, but generated nothing".to_string(),
+        Some(reason) => format!("empty message (done_reason: {})", reason),
+        None if response["done"] == json!(false) => "the response is incomplete".to_string(),
+        None => format!("empty message ({} tokens generated)", generated),
+    };
+    Err(WikiRagError::NoReply { model, reason }.into())
+}
+
+/// The parts of an Ollama request which make up the call, for the audit
+/// records.
+fn ollama_request_json(request: &ChatMessageRequest) -> serde_json::Value {
+    json!({
+        "model": request.model_name,
+        "messages": request.messages,
+    })
+}
+
+impl OllamaBackend<'_> {
+    fn build_answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> ChatMessageRequest {
+        let config = self.config;
+        let mut messages: String = "".to_string();
+        for w in separate_pages(config, pages).iter() {
+            messages.push_str(w);
+            messages.push('\n');
+        }
+        let mut chat = vec![];
+        let history = retained_history(config, history);
+        for (i, (q, a)) in conversation_turns(history, question).enumerate() {
+            if i == 0 {
+                messages.push_str(&instructed_question(config, pages, q));
+                chat.push(ChatMessage::system(std::mem::take(&mut messages)));
+            } else {
+                chat.push(ChatMessage::user(q.to_string()));
+            }
+            if let Some(a) = a {
+                chat.push(ChatMessage::assistant(a.to_string()));
+            }
+        }
+
+        let mut options = GenerationOptions::default().num_predict(config.answer_max_tokens as i32);
+        if let Some(t) = config.answer_temperature {
+            options = options.temperature(t);
+        }
+        let mut request = ChatMessageRequest::new(config.model.clone(), chat).options(options);
+        // Ollama only knows JSON mode, the schema itself is in the prompt.
+        if config.answer_schema.is_some() {
+            request = request.format(FormatType::Json);
+        }
+        request
+    }
+}
+
+#[async_trait(?Send)]
+impl LlmBackend for OllamaBackend<'_> {
+    async fn extract_keywords(&self, question: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+
+        let messages = vec![
+            ChatMessage::system(keyword_prompt(config, question)),
+            ChatMessage::user(question.to_string()),
+        ];
+        let request = ChatMessageRequest::new(config.model.clone(), messages)
+            .options(GenerationOptions::default().temperature(config.keyword_temperature));
+
+        let response = ollama_chat(config, &request).await?;
+        ollama_reply(config, &response)
+    }
+
+    async fn answer(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+        stream: bool,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let request = self.build_answer_request(pages, history, question);
+        if stream {
+            return ollama_stream(config, &request).await;
+        }
+        let response = ollama_chat(config, &request).await?;
+        ollama_reply(config, &response)
+    }
+
+    fn answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        Ok(ollama_request_json(
+            &self.build_answer_request(pages, history, question),
+        ))
+    }
+
+    async fn complete(
+        &self,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+
+        let messages = vec![
+            ChatMessage::system(system.to_string()),
+            ChatMessage::user(user.to_string()),
+        ];
+        let request = ChatMessageRequest::new(config.model.clone(), messages).options(
+            GenerationOptions::default()
+                .temperature(0.0)
+                .num_predict(max_tokens as i32),
+        );
+        let response = ollama_chat(config, &request).await?;
+        ollama_reply(config, &response)
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let model = config
+            .embedding_model
+            .clone()
+            .unwrap_or_else(|| "nomic-embed-text".into());
+        let url = format!("{}/api/embeddings", OLLAMA_URL);
+        let mut embeddings = vec![];
+        for text in texts {
+            let request = json!({ "model": model, "prompt": text });
+            let (request, url) = (&request, &url);
+            let response: serde_json::Value =
+                retry(config, "Ollama embeddings request", move || async move {
+                    let response = config.http.post(url).json(request).send().await?;
+                    let status = response.status();
+                    let body = response.text().await?;
+                    if !status.is_success() {
+                        return Err(ollama_status_error(status, body));
+                    }
+                    Ok(serde_json::from_str(&body)?)
+                })
+                .await?;
+            audit::record(config, "ollama-embeddings", request, &response);
+            let embedding: Vec<f64> = serde_json::from_value(response["embedding"].clone())?;
+            embeddings.push(embedding);
+        }
+        Ok(embeddings)
+    }
+}
+
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_MODELS_URL: &str = "https://api.anthropic.com/v1/models";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// The name of a model in the Anthropic API.
+pub fn anthropic_model_id(model: &str) -> &str {
+    match model {
+        "claude-3-5-sonnet" => "claude-3-5-sonnet-latest",
+        "claude-3-haiku" => "claude-3-haiku-20240307",
+        other => other,
+    }
+}
+
+#[derive(Deserialize)]
+struct AnthropicModels {
+    data: Vec<AnthropicModel>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicModel {
+    id: String,
+}
+
+/// The models the Anthropic API offers.
+pub async fn anthropic_models(config: &Config) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let models: AnthropicModels = config
+        .http
+        .get(ANTHROPIC_MODELS_URL)
+        .query(&[("limit", "1000")])
+        .header(
+            "x-api-key",
+            std::env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
+        )
+        .header("anthropic-version", ANTHROPIC_VERSION)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(models.data.into_iter().map(|m| m.id).collect())
+}
+
+#[derive(Deserialize, Serialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AnthropicUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl AnthropicResponse {
+    /// The text of the reply, an empty reply is an error.
+    fn text(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let text: String = self
+            .content
+            .iter()
+            .filter(|c| c.kind == "text")
+            .map(|c| c.text.as_str())
+            .collect();
+        if text.trim().is_empty() {
+            return Err(format!(
+                "Anthropic model returned an empty message (stop reason: {})",
+                self.stop_reason.as_deref().unwrap_or("none")
+            )
+            .into());
+        }
+        Ok(text)
+    }
+}
+
+impl AnthropicBackend<'_> {
+    /// The conversation, with the instructions before the first question.
+    fn answer_messages(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Vec<serde_json::Value> {
+        let mut messages = vec![];
+        for (i, (q, a)) in conversation_turns(history, question).enumerate() {
+            let content = if i == 0 {
+                instructed_question(self.config, pages, q)
+            } else {
+                q.to_string()
+            };
+            messages.push(json!({ "role": "user", "content": content }));
+            if let Some(a) = a {
+                messages.push(json!({ "role": "assistant", "content": a }));
+            }
+        }
+        messages
+    }
+
+    fn request_body(
+        &self,
+        system: &str,
+        messages: Vec<serde_json::Value>,
+        max_tokens: u32,
+        temperature: Option<f32>,
+    ) -> serde_json::Value {
+        let mut request = json!({
+            "model": anthropic_model_id(&self.config.model),
+            "max_tokens": max_tokens,
+            "messages": messages,
+        });
+        // An ANSWER_PROMPT with {pages} leaves no system text.
+        if !system.is_empty() {
+            request["system"] = json!(system);
+        }
+        if let Some(t) = temperature {
+            request["temperature"] = json!(t);
+        }
+        request
+    }
+
+    /// Sends a request to the messages API. `system` and `messages` are
+    /// as in the API, `temperature` is left to the model if not given.
+    async fn send(
+        &self,
+        system: &str,
+        messages: Vec<serde_json::Value>,
+        max_tokens: u32,
+        temperature: Option<f32>,
+    ) -> Result<AnthropicResponse, Box<dyn std::error::Error>> {
+        let config = self.config;
+        count_llm_call(config)?;
+        let key = std::env::var("ANTHROPIC_API_KEY").unwrap_or_default();
+        let request = self.request_body(system, messages, max_tokens, temperature);
+        let response: AnthropicResponse = {
+            let (request, key) = (&request, &key);
+            retry(config, "Anthropic request", move || async move {
+                let response = config
+                    .http
+                    .post(ANTHROPIC_URL)
+                    .header("x-api-key", key)
+                    .header("anthropic-version", ANTHROPIC_VERSION)
+                    .json(request)
+                    .send()
+                    .await?;
+                let status = response.status();
+                let body = response.text().await?;
+                if !status.is_success() {
+                    // Errors come as {"type": "error", "error": {"message": ...}}.
+                    let message = serde_json::from_str::<serde_json::Value>(&body)
+                        .ok()
+                        .and_then(|v| v["error"]["message"].as_str().map(String::from))
+                        .unwrap_or(body);
+                    return Err(HttpStatusError {
+                        status: status.as_u16(),
+                        message: format!("Anthropic request failed: {}", message),
+                    }
+                    .into());
+                }
+                Ok(serde_json::from_str(&body)?)
+            })
+            .await?
+        };
+        audit::record(config, "anthropic-messages", &request, &response);
+        print_token_usage(
+            config,
+            response.usage.input_tokens,
+            response.usage.output_tokens,
+        );
+        Ok(response)
+    }
+}
+
+#[async_trait(?Send)]
+impl LlmBackend for AnthropicBackend<'_> {
+    async fn extract_keywords(&self, question: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        let mut max_tokens = keyword_max_tokens(config);
+        let mut retries = 0;
+        loop {
+            let response = self
+                .send(
+                    &keyword_prompt(config, question),
+                    vec![json!({ "role": "user", "content": question })],
+                    max_tokens,
+                    Some(config.keyword_temperature),
+                )
+                .await?;
+            if response.stop_reason.as_deref() == Some("max_tokens")
+                && retries < KEYWORD_LENGTH_RETRIES
+            {
+                retries += 1;
+                progress!(
+                    config,
+                    "Keyword response was cut off at {} tokens, retrying with {}.",
+                    max_tokens,
+                    max_tokens * 2
+                );
+                max_tokens *= 2;
+                learn_keyword_tokens(&config.model, max_tokens);
+                continue;
+            }
+            return response.text();
+        }
+    }
+
+    async fn answer(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+        stream: bool,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let config = self.config;
+        let answer = self
+            .send(
+                &separate_pages(config, pages).join("\n"),
+                self.answer_messages(pages, history, question),
+                config.answer_max_tokens,
+                config.answer_temperature,
+            )
+            .await?
+            .text()?;
+        // Not streamed from the API, a streaming caller gets the answer
+        // in one piece.
+        if stream {
+            config.emit(PipelineEvent::AnswerChunk {
+                text: answer.clone(),
+            });
+        }
+        Ok(answer)
+    }
+
+    fn answer_request(
+        &self,
+        pages: &[String],
+        history: &[Exchange],
+        question: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        Ok(self.request_body(
+            &separate_pages(self.config, pages).join("\n"),
+            self.answer_messages(pages, history, question),
+            self.config.answer_max_tokens,
+            self.config.answer_temperature,
+        ))
+    }
+
+    async fn complete(
+        &self,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.send(
+            system,
+            vec![json!({ "role": "user", "content": user })],
+            max_tokens,
+            Some(0.0),
+        )
+        .await?
+        .text()
+    }
+
+    async fn embed(
+        &self,
+        _texts: Vec<String>,
+    ) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error>> {
+        Err("Anthropic has no embedding API, set EMBEDDING_PROVIDER to openai or ollama to use RERANK with Claude models".into())
+    }
+}
@@ -0,0 +1,101 @@
+//! Offline operation from a directory of pre-fetched page extracts, set
+//! with `WIKI_SOURCE=local:/path`. Every page is a file `{title}.txt`,
+//! with underscores for spaces, and the file name without `.txt` serves
+//! as page id.
+
+use crate::extract::WikiExtract;
+use crate::{UnusablePage, WikiPage};
+use regex::Regex;
+use std::path::Path;
+
+/// Characters of text shown around the first match as snippet.
+const SNIPPET_CHARS: usize = 100;
+
+/// All pages of the directory, as page id and text.
+fn pages(dir: &Path) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let mut pages = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        pages.push((id.to_string(), std::fs::read_to_string(&path)?));
+    }
+    Ok(pages)
+}
+
+fn title(page_id: &str) -> String {
+    page_id.replace('_', " ")
+}
+
+/// The text around the first occurrence of `at`, on one line.
+fn snippet(text: &str, at: usize) -> String {
+    let mut start = at.saturating_sub(SNIPPET_CHARS / 2);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let rest: String = text[start..].chars().take(SNIPPET_CHARS).collect();
+    rest.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Finds the pages matching the keyword, case-insensitively: those whose
+/// title is the keyword, then those whose title contains it, then those
+/// whose text contains it, most occurrences first.
+pub fn search(
+    dir: &Path,
+    keyword: &str,
+    limit: usize,
+) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
+    let needle = keyword.trim().to_lowercase();
+    let re = Regex::new(&format!("(?i){}", regex::escape(keyword.trim())))?;
+    let mut ranked: Vec<(usize, WikiPage)> = vec![];
+    for (id, text) in pages(dir)? {
+        let title = title(&id);
+        let score = if title.to_lowercase() == needle {
+            usize::MAX
+        } else if title.to_lowercase().contains(&needle) {
+            usize::MAX - 1
+        } else {
+            re.find_iter(&text).count()
+        };
+        if score == 0 {
+            continue;
+        }
+        let at = re.find(&text).map(|m| m.start()).unwrap_or(0);
+        ranked.push((
+            score,
+            WikiPage {
+                page_id: id,
+                title,
+                snippet: snippet(&text, at),
+                last_edited: None,
+                size: Some(text.len() as u64),
+                wordcount: Some(text.split_whitespace().count() as u64),
+            },
+        ));
+    }
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.title.cmp(&b.1.title)));
+    Ok(ranked.into_iter().take(limit).map(|(_, p)| p).collect())
+}
+
+/// The text of a page, or just its lead section with `intro_only`.
+pub fn read(
+    dir: &Path,
+    page_id: &str,
+    intro_only: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if page_id.contains(['/', '\\']) || page_id.starts_with('.') {
+        return Err(UnusablePage("the page does not exist").into());
+    }
+    match std::fs::read_to_string(dir.join(format!("{}.txt", page_id))) {
+        Ok(text) if intro_only => Ok(WikiExtract::parse(&text).intro),
+        Ok(text) => Ok(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(UnusablePage("the page does not exist").into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -1,434 +1,381 @@
-use async_openai::types::{
-    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-    ChatCompletionRequestUserMessageArgs, CompletionUsage, CreateChatCompletionRequestArgs,
+use std::io::{self, IsTerminal, Read};
+use wikirag::events::{EventSink, PipelineEvent};
+use wikirag::{
+    answer_question, apply_args, curid_url, dry_run, fall_back_if_unavailable, get_config_from_env,
+    greet, print_answer, print_session_total, print_unanswered, progress, prompt,
+    provider_for_model, report_error, retrieve, session, validate_answer_max_tokens,
+    validate_answer_schema, validate_providers, wiki_url, AnswerOutput, Config, Exchange,
+    OutputFormat, Retrieval, WikiRagError,
 };
-use async_openai::Client;
-use ollama_rs::{
-    generation::chat::{request::ChatMessageRequest, ChatMessage},
-    Ollama,
-};
-use reqwest::Client as ReqClient;
-use serde::Deserialize;
-use std::io;
-
-enum LlmProvider {
-    OpenAI,
-    Ollama,
-}
 
-struct Config {
-    pub model: String,
-    pub verbose: bool,
-    pub wiki_pages: u32,
-    pub llm_server: LlmProvider,
-}
+mod bench;
 
-fn get_config_from_env() -> Config {
-    // Defaults:
-    let mut c = Config {
-        model: "gpt-3.5-turbo".into(),
-        verbose: false,
-        wiki_pages: 1,
-        llm_server: LlmProvider::OpenAI,
-    };
-    if let Ok(val) = std::env::var("AI_MODEL") {
-        match val.as_ref() {
-            "gpt-4-turbo" | "gpt-3.5-turbo" | "gpt-4o" => {
-                c.model = val;
-                c.llm_server = LlmProvider::OpenAI;
+/// Renders the pipeline events as the command line output: progress on
+/// stderr, the final answer on stdout (and the output file).
+fn present(config: &Config, out: &mut AnswerOutput, event: &PipelineEvent) {
+    match event {
+        PipelineEvent::KeywordsDerived { keywords } => {
+            progress!(config, "Keywords found: {}", keywords.join(", "));
+        }
+        PipelineEvent::SearchCompleted { pages, .. } => {
+            progress!(config, "Wikipedia search results:");
+            let header: Vec<String> = config
+                .table_columns
+                .iter()
+                .map(|c| c.pad(c.header()))
+                .collect();
+            let header = header.join(" | ");
+            progress!(config, "{}", header);
+            progress!(config, "{}", "=".repeat(header.chars().count()));
+            for (i, p) in pages.iter().enumerate() {
+                let row: Vec<String> = config
+                    .table_columns
+                    .iter()
+                    .map(|c| c.cell(&config.language, i + 1, p))
+                    .collect();
+                progress!(config, "{}", row.join(" | "));
+            }
+            progress!(config, "");
+        }
+        PipelineEvent::PageDownloaded { title, size, .. } => {
+            progress!(
+                config,
+                "Wikipedia page downloaded '{}': Size: {}",
+                title,
+                size
+            );
+        }
+        // Unless streamed, the answer is printed as a whole once it is
+        // complete. JSON output never shows the pieces.
+        PipelineEvent::AnswerChunk { text } => {
+            if config.streams() && config.format != OutputFormat::Json {
+                out.emit(text);
             }
-            "llama3" => {
-                c.model = val;
-                c.llm_server = LlmProvider::Ollama;
+        }
+        PipelineEvent::Completed(report) => {
+            progress!(config, "\n");
+            let streamed = config.streams() && config.format != OutputFormat::Json;
+            let answer = if streamed { "" } else { report.answer.as_str() };
+            if let Err(e) = print_answer(config, out, report, answer) {
+                std::process::exit(report_error(&e));
             }
-            _ => {
-                eprintln!(
-                    "Unknown model {} requested, falling back to 'gpt-3.5-turbo'.
-Only the following models are currently allowed:
-  - gpt-4-turbo
-  - gpt-4o
-  - gpt-3.5-turbo
-",
-                    val
+            progress!(config, "\nSources:");
+            for p in report.pages.iter() {
+                progress!(
+                    config,
+                    "  {} {}",
+                    if config.local_source.is_some() {
+                        wiki_url(&config.language, &p.title)
+                    } else {
+                        curid_url(&config.language, &p.page_id)
+                    },
+                    p.title
                 );
             }
         }
     }
-    if let Ok(val) = std::env::var("VERBOSE") {
-        if !val.is_empty() {
-            c.verbose = true;
+}
+
+/// Reads the question from stdin. Piped input is read as a whole, so
+/// that it may span several lines, and must not be empty. On a terminal
+/// the user is asked for a line until it is a question, not a command.
+fn read_question(config: &Config) -> String {
+    if !io::stdin().is_terminal() {
+        let mut text = String::new();
+        if io::stdin().read_to_string(&mut text).is_err() || text.trim().is_empty() {
+            eprintln!("Error: no question provided.");
+            std::process::exit(WikiRagError::NoQuestion.exit_code());
         }
+        return text.trim().to_string();
     }
-    if let Ok(val) = std::env::var("WIKI_PAGES") {
-        if !val.is_empty() {
-            let n = val.parse::<u32>();
-            if let Ok(n) = n {
-                c.wiki_pages = n;
-                if c.wiki_pages == 0 {
-                    c.wiki_pages = 1;
-                }
+    loop {
+        prompt!(config, "Please enter your question:");
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            eprintln!("Error: no question provided.");
+            std::process::exit(WikiRagError::NoQuestion.exit_code());
+        }
+        let question = line.trim();
+        match question {
+            "/quit" | "/exit" => std::process::exit(0),
+            "/help" => {
+                eprintln!("Type a question to have it answered from Wikipedia, or /quit to exit.")
+            }
+            _ if question.starts_with('/') => {
+                eprintln!("Unknown command {}, type /help for help.", question)
             }
+            "" => eprintln!("Please enter a question, it must not be empty."),
+            _ => return question.to_string(),
         }
     }
-    c
 }
 
-fn greet() {
-    eprintln!(
-        "This is WikiRag!
-
-I will answer your question using knowledge from Wikipedia. I will first
-use a LLM to derive key words to perform a search in Wikipedia and will
-then retrieve the relevant pages. I will then feed these pages to the
-LLM and let it answer your questions in this way. In the end you get the
-answer plus a citation into Wikipedia.
-"
-    );
-}
-
-fn pretty_print_usage(config: &Config, usage: Option<CompletionUsage>) {
-    if let Some(usage) = usage {
-        let (in_costs, out_costs) = match config.model.as_ref() {
-            "gpt-4-turbo" => (
-                usage.prompt_tokens as f64 / 1_000_000.0 * 10.0,
-                usage.completion_tokens as f64 / 1_000_000.0 * 30.0,
-            ),
-            "gpt-3.5-turbo" => (
-                usage.prompt_tokens as f64 / 1_000_000.0 * 0.5,
-                usage.completion_tokens as f64 / 1_000_000.0 * 1.5,
-            ),
-            "gpt-4o" => (
-                usage.prompt_tokens as f64 / 1_000_000.0 * 5.0,
-                usage.completion_tokens as f64 / 1_000_000.0 * 15.0,
-            ),
-            _ => (0.0, 0.0),
-        };
-        eprintln!(
-            "Tokens in: {} (${:.6}), tokens out: {} (${:.6})",
-            usage.prompt_tokens, in_costs, usage.completion_tokens, out_costs
-        );
-    }
+/// What the user wants to do next in interactive mode.
+enum NextStep {
+    /// Answer a follow-up from the pages already retrieved, the default
+    /// for anything typed.
+    FollowUp(String),
+    /// Start over with a fresh keyword derivation and search.
+    NewQuestion(String),
+    /// Answer the last question again from the same pages, optionally
+    /// with another model or temperature.
+    Regenerate {
+        model: Option<String>,
+        temperature: Option<f32>,
+    },
+    Quit,
 }
 
-async fn get_keywords_from_chatgpt(
-    config: &Config,
-    question: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new();
-
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(32_u32)
-        .model(&config.model)
-        .messages([
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content("Extract exactly one keyword from the user's question for a Wikipedia lookup, respond with just the single keyword.".to_string())
-                .build()?.into(),
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(question)
-                .build()?.into(),
-        ])
-        .build()?;
-
-    let response = client.chat().create(request).await?;
-
-    pretty_print_usage(config, response.usage);
-
-    if let Some(choice) = response.choices.first() {
-        if let Some(msg) = &choice.message.content {
-            Ok(msg.clone())
-        } else {
-            Ok("Did not receive response!".to_string())
+/// Parses the options of `/regenerate [model=<model>] [temperature=<t>]`.
+fn parse_regenerate(args: &str) -> Result<NextStep, String> {
+    let mut model = None;
+    let mut temperature = None;
+    for arg in args.split_whitespace() {
+        match arg.split_once('=') {
+            Some(("model", m)) if provider_for_model(m).is_some() => model = Some(m.to_string()),
+            Some(("model", m)) => return Err(format!("Unknown model {}", m)),
+            Some(("temperature", t)) => match t.parse::<f32>() {
+                Ok(t) => temperature = Some(t),
+                Err(_) => return Err(format!("Invalid temperature {}", t)),
+            },
+            _ => return Err(format!("Unknown option {}", arg)),
         }
-    } else {
-        Ok("No keywords found".to_string())
     }
+    Ok(NextStep::Regenerate { model, temperature })
 }
 
-async fn answer_question_with_wikipage_openai(
-    config: &Config,
-    wikipage: &Vec<String>,
-    question: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new();
-
-    let mut messages: Vec<ChatCompletionRequestMessage> = vec![];
-    for w in wikipage.iter() {
-        messages.push(
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content(w)
-                .name("Wikipedia".to_string())
-                .build()?
-                .into(),
-        );
-    }
-    messages.push(
-        ChatCompletionRequestUserMessageArgs::default()
-            .content(format!(
-                "Now answer the following question, using the information in the provided text: {}",
-                question
-            ))
-            .build()?
-            .into(),
+fn read_next_step(config: &Config) -> NextStep {
+    prompt!(
+        config,
+        "\nAsk a follow-up question about the pages already retrieved, use '/new <question>'
+to search Wikipedia again, '/regenerate [model=<model>] [temperature=<t>]' to answer
+the last question again, or '/quit' (or Enter) to quit:"
     );
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(1000_u32)
-        .model(&config.model)
-        .messages(messages)
-        .build()?;
-
-    let response = client.chat().create(request).await?;
-
-    pretty_print_usage(config, response.usage);
-
-    if let Some(choice) = response.choices.first() {
-        if let Some(msg) = &choice.message.content {
-            Ok(msg.clone())
-        } else {
-            Ok("No response received".to_string())
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+        return NextStep::Quit;
+    }
+    let line = line.trim();
+    if let Some(q) = line.strip_prefix("/new") {
+        let q = q.trim();
+        if q.is_empty() {
+            return NextStep::NewQuestion(read_question(config));
+        }
+        NextStep::NewQuestion(q.to_string())
+    } else if let Some(q) = line.strip_prefix("/followup") {
+        let q = q.trim();
+        if q.is_empty() {
+            eprintln!("Usage: /followup <question>");
+            return read_next_step(config);
         }
+        NextStep::FollowUp(q.to_string())
+    } else if let Some(args) = line.strip_prefix("/regenerate") {
+        match parse_regenerate(args) {
+            Ok(step) => step,
+            Err(e) => {
+                eprintln!(
+                    "{}. Usage: /regenerate [model=<model>] [temperature=<t>]",
+                    e
+                );
+                read_next_step(config)
+            }
+        }
+    } else if line.is_empty() || line == "/quit" || line == "/exit" {
+        NextStep::Quit
+    } else if line == "/help" {
+        eprintln!("Commands: /new [<question>], /followup <question>, /regenerate, /quit");
+        read_next_step(config)
+    } else if line.starts_with('/') {
+        eprintln!("Unknown command {}, type /help for help.", line);
+        read_next_step(config)
     } else {
-        Ok("No keywords found".to_string())
+        NextStep::FollowUp(line.to_string())
     }
 }
 
-async fn get_keywords_from_ollama(
-    config: &Config,
-    question: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut ollama = Ollama::new_default_with_history(30);
-
-    let user_msg = ChatMessage::system("Extract exactly one keyword from the user's question for a Wikipedia lookup, respond with just the single keyword. ".to_string() + question);
-
-    let response = ollama
-        .send_chat_messages_with_history(
-            ChatMessageRequest::new(config.model.clone(), vec![user_msg]),
-            "default".to_string(),
-        )
-        .await?;
-
-    if let Some(msg) = response.message {
-        Ok(msg.content.clone())
-    } else {
-        Ok("Did not receive response!".to_string())
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("bench") {
+        bench::run(&args[1..]).await;
+        return;
     }
-}
 
-async fn answer_question_with_wikipage_ollama(
-    config: &Config,
-    wikipage: &Vec<String>,
-    question: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut ollama = Ollama::new_default_with_history(30);
-
-    let mut messages: String = "".to_string();
-    for w in wikipage.iter() {
-        messages.push_str(w);
-        messages.push_str("\n");
+    let mut config = get_config_from_env();
+    let question = apply_args(&mut config).unwrap_or_else(|e| std::process::exit(report_error(&e)));
+    if let Some(q) = &question {
+        if q.trim().is_empty() {
+            eprintln!("Error: the question must not be empty.");
+            std::process::exit(WikiRagError::NoQuestion.exit_code());
+        }
     }
-    messages.push_str(&format!(
-        "Now answer the following question, using the information in the provided text: {}",
-        question
-    ));
-    let user_msg = ChatMessage::system(messages);
 
-    let response = ollama
-        .send_chat_messages_with_history(
-            ChatMessageRequest::new(config.model.clone(), vec![user_msg]),
-            "default".to_string(),
-        )
-        .await?;
-
-    if let Some(msg) = response.message {
-        Ok(msg.content.clone())
-    } else {
-        Ok("No response received".to_string())
+    if config.chatty() {
+        greet();
     }
-}
-
-#[derive(Deserialize, Debug)]
-struct SearchResult {
-    title: String,
-    pageid: u32,
-}
-
-#[derive(Deserialize, Debug)]
-struct QueryResult {
-    search: Vec<SearchResult>,
-}
-
-#[derive(Deserialize, Debug)]
-struct WikipediaResponse {
-    query: QueryResult,
-}
-
-#[derive(Debug)]
-struct WikiPage {
-    pub page_id: String,
-    pub title: String,
-}
-
-async fn search_wikipedia(
-    config: &Config,
-    keyword: &str,
-) -> Result<Vec<WikiPage>, Box<dyn std::error::Error>> {
-    let client = ReqClient::new();
-    let base_url = "https://en.wikipedia.org/w/api.php";
-
-    let params = [
-        ("action", "query"),
-        ("list", "search"),
-        ("srsearch", keyword),
-        ("format", "json"),
-    ];
-
-    let response = client.get(base_url).query(&params).send().await?;
-    let body = response.text().await?;
-
     if config.verbose {
-        eprintln!("Raw response: {}", body);
+        eprintln!(
+            "Temperatures: keyword extraction {}, answer {}",
+            config.keyword_temperature,
+            config
+                .answer_temperature
+                .map(|t| t.to_string())
+                .unwrap_or_else(|| "provider default".into())
+        );
+        eprintln!("Reading level: {}", config.reading_level.name());
     }
 
-    let response: WikipediaResponse = serde_json::from_str(&body)?;
-
-    let pages: Vec<WikiPage> = response
-        .query
-        .search
-        .iter()
-        .map(|result| WikiPage {
-            page_id: result.pageid.to_string(),
-            title: result.title.to_string(),
-        })
-        .collect();
-
-    Ok(pages)
-}
-
-#[derive(Deserialize, Debug)]
-struct Page {
-    extract: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct QueryPages {
-    pages: std::collections::HashMap<String, Page>,
-}
-
-#[derive(Deserialize, Debug)]
-struct WikipediaExtractResponse {
-    query: QueryPages,
-}
-
-async fn download_wikipedia_page(
-    config: &Config,
-    page_id: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = ReqClient::new();
-    let base_url = "https://en.wikipedia.org/w/api.php";
-
-    let params = [
-        ("action", "query"),
-        ("pageids", page_id),
-        ("prop", "extracts"),
-        ("explaintext", "true"),
-        ("format", "json"),
-    ];
-
-    let response = client.get(base_url).query(&params).send().await?;
-    let body = response.text().await?;
-
-    if config.verbose {
-        eprintln!("Raw response: {}", body);
+    fall_back_if_unavailable(&mut config).await;
+    if let Err(e) = validate_providers(&config).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
     }
-
-    let response: WikipediaExtractResponse = serde_json::from_str(&body)?;
-
-    if let Some(page) = response.query.pages.get(page_id) {
-        Ok(page.extract.clone())
-    } else {
-        Err("Page not found".into())
+    if let Err(e) = validate_answer_max_tokens(&config) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
     }
-}
-
-fn deal_with_error<T>(r: Result<T, Box<dyn std::error::Error>>, ec: i32) -> T {
-    match r {
-        Err(e) => {
-            println!("Error: {}", e);
-            std::process::exit(ec);
-        }
-        Ok(t) => t,
+    if let Err(e) = validate_answer_schema(&config) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
     }
-}
-
-#[tokio::main]
-async fn main() {
-    greet();
 
-    let config = get_config_from_env();
-
-    // Read question:
-    let mut question = String::new();
-    eprintln!("Please enter your question:");
-    io::stdin().read_line(&mut question).unwrap();
-
-    eprintln!(
-        "\nPerforming keyword derivation using LLM model {}...",
-        config.model
-    );
-    let res = match config.llm_server {
-        LlmProvider::OpenAI => get_keywords_from_chatgpt(&config, &question.trim()).await,
-        LlmProvider::Ollama => get_keywords_from_ollama(&config, &question.trim()).await,
+    // A saved session continues with its pages and conversation.
+    let resumed = config.session_file.as_deref().and_then(session::load);
+    let (mut retrieval, mut history, mut step) = match resumed {
+        Some(s) => {
+            config.interactive = true;
+            progress!(
+                config,
+                "Resuming session about '{}' with {} pages and {} questions.",
+                s.retrieval.keywords,
+                s.retrieval.page_strings.len(),
+                s.history.len()
+            );
+            let step = match question {
+                Some(q) => NextStep::FollowUp(q),
+                None => read_next_step(&config),
+            };
+            (s.retrieval, s.history, step)
+        }
+        None => {
+            // A question on the command line means there is nothing to ask.
+            let q = question.unwrap_or_else(|| read_question(&config));
+            (Retrieval::default(), vec![], NextStep::NewQuestion(q))
+        }
     };
-    let keywords: String = deal_with_error(res, 1);
-    eprintln!("Keywords found: {}", keywords);
 
-    eprintln!("\nPerforming lookup in Wikipedia using '{}'...", keywords);
-    let res = search_wikipedia(&config, &keywords).await;
-    let pages = deal_with_error(res, 2);
-    eprintln!("Wikipedia search results:");
-    eprintln!("  page id | title                | URL");
-    eprintln!("==========|======================|========");
-    for p in pages.iter() {
-        eprintln!(
-            "{:>10}| {:<30} | https://en.wikipedia.org/wiki/{}",
-            p.page_id,
-            p.title,
-            p.title.replace(" ", "_"),
-        );
-    }
-    eprintln!("");
+    let mut out =
+        AnswerOutput::new(&config).unwrap_or_else(|e| std::process::exit(report_error(&e)));
+    config.events = EventSink::handler(move |config, event| present(config, &mut out, event));
+
+    // The retrieved pages are kept for follow-up questions and are only
+    // refreshed when a new question is asked.
+    let mut regenerations = 0;
+    loop {
+        let mut overrides = None;
+        let mut replaced = None;
+        let question = match step {
+            NextStep::FollowUp(q) => q,
+            NextStep::NewQuestion(q) => {
+                match retrieve(&config, &q).await {
+                    Ok(r) => retrieval = r,
+                    // Interactively, the pages retrieved before stay.
+                    Err(e) => {
+                        let code = report_error(&e);
+                        if !config.interactive {
+                            print_session_total(&config);
+                            std::process::exit(code);
+                        }
+                        step = read_next_step(&config);
+                        continue;
+                    }
+                }
+                history.clear();
+                regenerations = 0;
+                q
+            }
+            NextStep::Regenerate { model, temperature } => match history.pop() {
+                Some(last) => {
+                    overrides = Some((model, temperature));
+                    replaced = Some(last.answer);
+                    last.question
+                }
+                None => {
+                    eprintln!("There is no answer to regenerate yet.");
+                    step = read_next_step(&config);
+                    continue;
+                }
+            },
+            NextStep::Quit => {
+                print_session_total(&config);
+                return;
+            }
+        };
 
-    // Download pages:
-    let mut page_strings: Vec<String> = vec![];
-    for i in 0..config.wiki_pages as usize {
-        if i >= pages.len() {
-            break;
-        }
-        let res = download_wikipedia_page(&config, &pages[i].page_id).await;
-        let page = deal_with_error(res, 3);
-        eprintln!(
-            "Wikipedia page downloaded '{}': Size: {}",
-            pages[i].title,
-            page.len(),
+        // Other settings for a regeneration only apply to it:
+        let saved = (
+            config.model.clone(),
+            config.llm_server,
+            config.answer_temperature,
         );
-        page_strings.push(page);
-    }
+        if let Some((model, temperature)) = &overrides {
+            if let Some(model) = model {
+                config.model = model.clone();
+                config.llm_server = provider_for_model(model).unwrap();
+            }
+            if temperature.is_some() {
+                config.answer_temperature = *temperature;
+            }
+        }
+        if config.dry_run {
+            let res = dry_run(&config, &retrieval, &history, &question);
+            print_session_total(&config);
+            if let Err(e) = res {
+                std::process::exit(report_error(&e));
+            }
+            return;
+        }
+        let answer = answer_question(&config, &retrieval, &history, &question).await;
+        (config.model, config.llm_server, config.answer_temperature) = saved;
+        // Without an answer the pages found are still worth reading.
+        let answer = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                print_unanswered(&config, &retrieval, &question, &e);
+                let code = report_error(&e);
+                // A failed regeneration keeps the answer it would replace.
+                if let Some(answer) = replaced {
+                    history.push(Exchange { question, answer });
+                }
+                if !config.interactive {
+                    print_session_total(&config);
+                    std::process::exit(code);
+                }
+                step = read_next_step(&config);
+                continue;
+            }
+        };
+        progress!(config, "LLM calls so far: {}", config.usage.calls());
+        if overrides.is_some() {
+            regenerations += 1;
+            progress!(
+                config,
+                "Regenerated {} times, cost of this session so far: ${:.6}",
+                regenerations,
+                config.usage.cost()
+            );
+        }
 
-    eprintln!("\nAnswering question using Wikipedia pages and LLM model...");
-    let res = match config.llm_server {
-        LlmProvider::OpenAI => {
-            answer_question_with_wikipage_openai(&config, &page_strings, &question).await
+        history.push(Exchange { question, answer });
+        if let Some(path) = &config.session_file {
+            if let Err(e) = session::save(path, &retrieval, &history) {
+                eprintln!("Could not save the session to {}: {}", path, e);
+            }
         }
-        LlmProvider::Ollama => {
-            answer_question_with_wikipage_ollama(&config, &page_strings, &question).await
+        if !config.interactive {
+            print_session_total(&config);
+            return;
         }
-    };
-    let answer = deal_with_error(res, 4);
-    eprintln!("\n");
-    println!("{} \n\n***Links***:", answer);
-    for p in pages.iter() {
-        println!(
-            "https://en.wikipedia.org/wiki/{}",
-            p.title.replace(" ", "_")
-        );
+        step = read_next_step(&config);
     }
 }
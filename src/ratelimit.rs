@@ -0,0 +1,112 @@
+//! Token-bucket rate limiting for LLM calls, so that we stay below the
+//! requests-per-minute and tokens-per-minute limits of a provider account
+//! instead of running into 429 responses.
+
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    per_second: f64,
+}
+
+impl Bucket {
+    fn per_minute(limit: u32) -> Bucket {
+        Bucket {
+            capacity: limit as f64,
+            available: limit as f64,
+            per_second: limit as f64 / 60.0,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.available =
+            (self.available + elapsed.as_secs_f64() * self.per_second).min(self.capacity);
+    }
+
+    /// How long to wait until `amount` is available. A request larger than
+    /// the whole bucket only waits for a full bucket.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let amount = amount.min(self.capacity);
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) / self.per_second)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+}
+
+struct State {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    last: Instant,
+}
+
+pub struct RateLimiter {
+    state: Option<Mutex<State>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter for the given requests and tokens per minute.
+    /// Without any limits the limiter never waits.
+    pub fn new(rpm: Option<u32>, tpm: Option<u32>) -> RateLimiter {
+        let rpm = rpm.filter(|n| *n > 0);
+        let tpm = tpm.filter(|n| *n > 0);
+        if rpm.is_none() && tpm.is_none() {
+            return RateLimiter { state: None };
+        }
+        RateLimiter {
+            state: Some(Mutex::new(State {
+                requests: rpm.map(Bucket::per_minute),
+                tokens: tpm.map(Bucket::per_minute),
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Waits until one request using an estimated `tokens` tokens fits
+    /// into the limits and reserves it.
+    pub async fn acquire(&self, tokens: u32) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        loop {
+            let wait = {
+                let mut s = state.lock().await;
+                let now = Instant::now();
+                let elapsed = now - s.last;
+                s.last = now;
+                let mut wait = Duration::ZERO;
+                if let Some(b) = s.requests.as_mut() {
+                    b.refill(elapsed);
+                    wait = wait.max(b.wait_for(1.0));
+                }
+                if let Some(b) = s.tokens.as_mut() {
+                    b.refill(elapsed);
+                    wait = wait.max(b.wait_for(tokens as f64));
+                }
+                if wait.is_zero() {
+                    if let Some(b) = s.requests.as_mut() {
+                        b.take(1.0);
+                    }
+                    if let Some(b) = s.tokens.as_mut() {
+                        b.take(tokens as f64);
+                    }
+                    return;
+                }
+                wait
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A rough token estimate for budgeting: about four characters per token.
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.len() / 4) as u32 + 1
+}
@@ -0,0 +1,182 @@
+//! Retrying of transient failures of requests to Wikipedia and the LLM
+//! providers, with exponential backoff.
+
+use crate::{is_connect_error, progress, Config};
+use async_openai::error::OpenAIError;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Delay before the first retry, it doubles with every further one.
+const FIRST_DELAY: Duration = Duration::from_millis(200);
+
+/// An error the MediaWiki API reports in the body of a response, which
+/// still has status 200.
+#[derive(Debug)]
+pub struct WikiApiError {
+    pub code: String,
+    pub info: String,
+}
+
+impl fmt::Display for WikiApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Wikipedia API error {}: {}", self.code, self.info)
+    }
+}
+
+impl std::error::Error for WikiApiError {}
+
+/// The error reported in a MediaWiki API response body, if any.
+pub fn wiki_api_error(body: &str) -> Option<WikiApiError> {
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let error = value.get("error")?;
+    Some(WikiApiError {
+        code: error.get("code")?.as_str()?.to_string(),
+        info: error
+            .get("info")
+            .and_then(|i| i.as_str())
+            .unwrap_or("")
+            .to_string(),
+    })
+}
+
+/// An error response of an HTTP API, with the message from its body.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (HTTP status {})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Whether a failed request is worth another try, with the reason.
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    Transient(String),
+    Permanent(String),
+}
+
+/// Server errors and rate limiting are worth another try, client errors
+/// like bad parameters (400) or a missing page (404) are not.
+fn classify_status(status: u16) -> Failure {
+    if status == 429 || (500..600).contains(&status) {
+        Failure::Transient(format!("HTTP status {}", status))
+    } else {
+        Failure::Permanent(format!("HTTP status {}", status))
+    }
+}
+
+/// Classifies a reqwest error by its status, if it has one.
+fn classify_reqwest(re: &reqwest::Error) -> Failure {
+    if re.is_timeout() {
+        return Failure::Transient("timeout".into());
+    }
+    match re.status() {
+        Some(status) => classify_status(status.as_u16()),
+        None => Failure::Permanent("request failed".into()),
+    }
+}
+
+/// MediaWiki API errors which go away by themselves: the replicas lag
+/// behind, too many requests, or the wiki is read-only for maintenance.
+fn classify_api_code(code: &str) -> Failure {
+    match code {
+        "maxlag" | "ratelimited" | "readonly" => Failure::Transient(format!("API error {}", code)),
+        _ => Failure::Permanent(format!("API error {}", code)),
+    }
+}
+
+/// Whether a failure may go away when the request is repeated: the
+/// connection failed or timed out, or the server had a problem. Client
+/// errors like a bad request are permanent.
+pub fn classify(e: &(dyn std::error::Error + 'static)) -> Failure {
+    if is_connect_error(e) {
+        return Failure::Transient("connection failed".into());
+    }
+    let mut current = Some(e);
+    while let Some(err) = current {
+        if let Some(re) = err.downcast_ref::<reqwest::Error>() {
+            return classify_reqwest(re);
+        }
+        if let Some(he) = err.downcast_ref::<HttpStatusError>() {
+            return classify_status(he.status);
+        }
+        if let Some(we) = err.downcast_ref::<WikiApiError>() {
+            return classify_api_code(&we.code);
+        }
+        if let Some(oe) = err.downcast_ref::<OpenAIError>() {
+            return match oe {
+                // async-openai has its own version of reqwest:
+                OpenAIError::Reqwest(re) if re.is_timeout() => Failure::Transient("timeout".into()),
+                OpenAIError::Reqwest(re) => match re.status() {
+                    Some(status) => classify_status(status.as_u16()),
+                    None => Failure::Permanent("request failed".into()),
+                },
+                OpenAIError::ApiError(api) if api.r#type.as_deref() == Some("server_error") => {
+                    Failure::Transient("server error".into())
+                }
+                _ => Failure::Permanent("API error".into()),
+            };
+        }
+        current = err.source();
+    }
+    Failure::Permanent("not a network or server problem".into())
+}
+
+/// Runs `op`, and repeats it after a growing delay as long as it fails
+/// transiently, at most `MAX_RETRIES` times.
+pub async fn retry<T, F, Fut>(
+    config: &Config,
+    what: &str,
+    mut op: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let mut delay = FIRST_DELAY;
+    let mut attempt = 0;
+    loop {
+        let e = match op().await {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+        match classify(e.as_ref()) {
+            Failure::Transient(reason) if attempt < config.max_retries => {
+                attempt += 1;
+                if config.verbose {
+                    eprintln!("{} failed transiently ({})", what, reason);
+                }
+                progress!(
+                    config,
+                    "{} failed ({}), retrying in {} ms ({} of {})...",
+                    what,
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    config.max_retries
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Failure::Transient(reason) => {
+                if config.verbose {
+                    eprintln!("{} failed transiently ({}), out of retries", what, reason);
+                }
+                return Err(e);
+            }
+            Failure::Permanent(reason) => {
+                if config.verbose {
+                    eprintln!("{} failed permanently ({}), not retrying", what, reason);
+                }
+                return Err(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Interactive conversations saved to disk, so that they can be resumed
+//! later with the same pages and history.
+
+use crate::{Exchange, Retrieval};
+use serde::{Deserialize, Serialize};
+
+/// Version of the session file format. Files of another version are not
+/// resumed, a new session is started instead.
+const SESSION_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+pub struct Session {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub retrieval: Retrieval,
+    #[serde(default)]
+    pub history: Vec<Exchange>,
+}
+
+#[derive(Serialize)]
+struct SavedSession<'a> {
+    version: u32,
+    retrieval: &'a Retrieval,
+    history: &'a [Exchange],
+}
+
+/// Loads the session from `path`. A missing file means a new session;
+/// unreadable files or other versions are reported and ignored.
+pub fn load(path: &str) -> Option<Session> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!("Could not read session file {}: {}", path, e);
+            return None;
+        }
+    };
+    let session: Session = match serde_json::from_str(&text) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!(
+                "Session file {} is not usable ({}), starting a new session.",
+                path, e
+            );
+            return None;
+        }
+    };
+    if session.version != SESSION_VERSION {
+        eprintln!(
+            "Session file {} has version {}, but version {} is needed. Starting a new session.",
+            path, session.version, SESSION_VERSION
+        );
+        return None;
+    }
+    if session.retrieval.page_strings.is_empty() {
+        return None;
+    }
+    Some(session)
+}
+
+pub fn save(
+    path: &str,
+    retrieval: &Retrieval,
+    history: &[Exchange],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = SavedSession {
+        version: SESSION_VERSION,
+        retrieval,
+        history,
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&session)?)?;
+    Ok(())
+}
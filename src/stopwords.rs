@@ -0,0 +1,95 @@
+//! Keyword extraction without an LLM: the question minus its stopwords.
+//!
+//! Built-in stopword lists exist for the languages in [`BUILTIN`]; to
+//! support another language, add an entry with its Wikipedia language
+//! code there. A list read from `STOPWORDS_FILE` replaces the built-in
+//! one, for example to keep words which are domain terms.
+
+const BUILTIN: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "a", "about", "an", "and", "are", "as", "at", "be", "been", "by", "can", "could",
+            "did", "do", "does", "for", "from", "had", "has", "have", "how", "i", "in", "is", "it",
+            "its", "me", "many", "much", "of", "on", "or", "tell", "that", "the", "their", "there",
+            "this", "to", "was", "were", "what", "when", "where", "which", "who", "whom", "why",
+            "will", "with", "would", "you",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "am", "an", "auf", "aus", "bei", "bin", "bist", "da", "das", "dem", "den", "der",
+            "des", "die", "du", "ein", "eine", "einem", "einen", "einer", "es", "für", "hat",
+            "hatte", "ich", "im", "in", "ist", "mir", "mit", "sind", "und", "viel", "viele", "von",
+            "war", "waren", "warum", "was", "welche", "welcher", "welches", "wer", "wie",
+            "wieviel", "wo", "zu", "zum", "zur",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "a", "au", "aux", "avec", "ce", "cette", "combien", "comment", "dans", "de", "des",
+            "du", "elle", "en", "est", "et", "il", "je", "la", "le", "les", "leur", "ont", "ou",
+            "où", "par", "pour", "pourquoi", "quand", "que", "quel", "quelle", "qui", "quoi",
+            "sont", "sur", "un", "une",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "a", "al", "con", "cuál", "cuándo", "cuánto", "cómo", "de", "del", "dónde", "el", "en",
+            "es", "está", "fue", "la", "las", "lo", "los", "para", "por", "qué", "quién", "se",
+            "son", "su", "un", "una", "y",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "a", "al", "che", "chi", "come", "con", "cosa", "da", "del", "della", "di", "dove",
+            "e", "era", "gli", "il", "in", "la", "le", "lo", "per", "perché", "quale", "quando",
+            "quanto", "sono", "su", "un", "una", "è",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "aan", "de", "een", "en", "had", "heeft", "het", "hoe", "hoeveel", "in", "is", "met",
+            "niet", "of", "op", "van", "voor", "waar", "waarom", "wanneer", "was", "wat", "welke",
+            "wie", "zijn",
+        ],
+    ),
+];
+
+/// The built-in stopwords of a language, English for languages without
+/// a list.
+pub fn builtin(language: &str) -> Vec<String> {
+    BUILTIN
+        .iter()
+        .find(|(lang, _)| *lang == language)
+        .unwrap_or(&BUILTIN[0])
+        .1
+        .iter()
+        .map(|w| w.to_string())
+        .collect()
+}
+
+/// Reads a stopword file: one word per line, empty lines and lines
+/// starting with `#` are ignored.
+pub fn load(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_lowercase())
+        .collect())
+}
+
+/// The words of the question which are not stopwords, in their order.
+pub fn keywords(question: &str, stopwords: &[String]) -> String {
+    question
+        .split(|c: char| !c.is_alphanumeric() && c != '-' && c != '\'')
+        .filter(|w| !w.is_empty() && !stopwords.contains(&w.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
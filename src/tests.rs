@@ -0,0 +1,869 @@
+//! Tests of the Wikipedia API handling against a mock server returning
+//! canned responses.
+
+use super::*;
+use wiremock::matchers::{header_regex, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// The default configuration, independent of the environment the tests
+/// run in, without the cache and without retries.
+fn fixed_config() -> Config {
+    let mut config = default_config();
+    config.page_cache = false;
+    config.max_retries = 0;
+    config.stream = false;
+    config
+}
+
+/// A configuration talking to the mock server.
+fn test_config(server: &MockServer) -> Config {
+    let mut config = fixed_config();
+    config.wiki_api = Some(format!("{}/w/api.php", server.uri()));
+    config
+}
+
+async fn respond(server: &MockServer, param: (&str, &str), body: serde_json::Value) {
+    Mock::given(method("GET"))
+        .and(path("/w/api.php"))
+        .and(query_param(param.0, param.1))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn search_returns_the_pages() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("list", "search"),
+        serde_json::json!({
+            "query": {
+                "searchinfo": { "totalhits": 2 },
+                "search": [
+                    { "pageid": 736, "title": "Albert Einstein", "snippet": "<span>Einstein</span>", "size": 20000, "wordcount": 3000 },
+                    { "pageid": 1, "title": "Einstein family" }
+                ]
+            }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let pages = search_wikipedia(&config, "Einstein").await.unwrap();
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[0].page_id, "736");
+    assert_eq!(pages[0].title, "Albert Einstein");
+    assert_eq!(pages[0].snippet, "<span>Einstein</span>");
+    assert_eq!(pages[0].size, Some(20000));
+    assert_eq!(pages[0].wordcount, Some(3000));
+    assert_eq!(pages[1].snippet, "");
+    assert_eq!(pages[1].size, None);
+}
+
+#[tokio::test]
+async fn search_without_results_is_empty() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("list", "search"),
+        serde_json::json!({ "query": { "searchinfo": { "totalhits": 0 }, "search": [] } }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let pages = search_wikipedia(&config, "Qwxzzy").await.unwrap();
+    assert!(pages.is_empty());
+}
+
+#[tokio::test]
+async fn malformed_search_response_is_a_parse_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("<html>not json</html>"))
+        .mount(&server)
+        .await;
+    let config = test_config(&server);
+
+    let e = search_wikipedia(&config, "Einstein").await.unwrap_err();
+    assert!(matches!(WikiRagError::search(e), WikiRagError::Parse(_)));
+}
+
+#[tokio::test]
+async fn api_error_in_the_body_fails_the_search() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("list", "search"),
+        serde_json::json!({ "error": { "code": "badvalue", "info": "Unrecognized value" } }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let e = search_wikipedia(&config, "Einstein").await.unwrap_err();
+    let api = e.downcast_ref::<retry::WikiApiError>().unwrap();
+    assert_eq!(api.code, "badvalue");
+    assert!(matches!(WikiRagError::search(e), WikiRagError::Search(_)));
+}
+
+#[tokio::test]
+async fn download_returns_the_extract() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({
+            "query": { "pages": [ { "pageid": 736, "title": "Albert Einstein", "extract": "Albert Einstein was a physicist." } ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let (text, _) = download_wikipedia_page(&config, "736").await.unwrap();
+    assert_eq!(text, "Albert Einstein was a physicist.");
+}
+
+#[tokio::test]
+async fn intro_only_asks_for_the_lead_section() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("exintro", "true"),
+        serde_json::json!({
+            "query": { "pages": [ { "pageid": 736, "title": "Albert Einstein", "extract": "Albert Einstein was a physicist." } ] }
+        }),
+    )
+    .await;
+    let mut config = test_config(&server);
+    config.intro_only = true;
+
+    let (text, _) = download_wikipedia_page(&config, "736").await.unwrap();
+    assert_eq!(text, "Albert Einstein was a physicist.");
+}
+
+#[tokio::test]
+async fn redirect_is_followed_to_the_article() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(query_param("redirects", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "query": {
+                "redirects": [ { "from": "NYC", "to": "New York City" } ],
+                "pages": [ { "pageid": 645042, "title": "New York City", "extract": "New York City is the most populous city in the United States." } ]
+            }
+        })))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({ "query": { "pages": [ { "pageid": 8003, "title": "NYC", "extract": "" } ] } }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let (text, redirect) = download_wikipedia_page(&config, "8003").await.unwrap();
+    assert!(text.starts_with("New York City is"));
+    assert_eq!(redirect.as_deref(), Some("New York City"));
+}
+
+#[tokio::test]
+async fn missing_page_is_unusable() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({ "query": { "pages": [ { "pageid": 5, "missing": true } ] } }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let e = download_wikipedia_page(&config, "5").await.unwrap_err();
+    assert!(e.is::<UnusablePage>());
+}
+
+#[tokio::test]
+async fn batch_lists_pages_without_extract_as_failed() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({
+            "query": { "pages": [
+                { "pageid": 1, "title": "One", "extract": "The first page, with enough text to not be a redirect." },
+                { "pageid": 2, "title": "Two" },
+                { "pageid": 3, "missing": true }
+            ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let batch = download_wikipedia_pages(&config, &["1", "2", "3"])
+        .await
+        .unwrap();
+    assert_eq!(batch.extracts.len(), 1);
+    assert!(batch.extracts["1"].starts_with("The first page"));
+    assert_eq!(batch.failed, vec!["2", "3"]);
+    assert!(batch.skipped.is_empty());
+}
+
+#[tokio::test]
+async fn batch_follows_the_continuation_for_whole_articles() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(query_param("excontinue", "1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "batchcomplete": true,
+            "query": { "pages": [
+                { "pageid": 1, "title": "One" },
+                { "pageid": 2, "title": "Two", "extract": "The second page, with enough text to not be a redirect." }
+            ] }
+        })))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({
+            "continue": { "excontinue": 1, "continue": "||" },
+            "query": { "pages": [
+                { "pageid": 1, "title": "One", "extract": "The first page, with enough text to not be a redirect." },
+                { "pageid": 2, "title": "Two" }
+            ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let batch = download_wikipedia_pages(&config, &["1", "2"])
+        .await
+        .unwrap();
+    assert_eq!(batch.extracts.len(), 2);
+    assert!(batch.extracts["2"].starts_with("The second page"));
+    assert!(batch.failed.is_empty() && batch.skipped.is_empty());
+}
+
+#[tokio::test]
+async fn pages_left_out_by_the_extract_limit_are_not_failed() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("prop", "extracts"),
+        serde_json::json!({
+            "continue": { "excontinue": 1, "continue": "||" },
+            "query": { "pages": [
+                { "pageid": 1, "title": "One", "extract": "The first page, with enough text to not be a redirect." },
+                { "pageid": 2, "title": "Two" },
+                { "pageid": 3, "missing": true }
+            ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let batch = download_wikipedia_pages(&config, &["1", "2", "3"])
+        .await
+        .unwrap();
+    assert_eq!(batch.extracts.len(), 1);
+    assert_eq!(batch.skipped, vec!["2"]);
+    assert_eq!(batch.failed, vec!["3"]);
+}
+
+#[tokio::test]
+async fn requests_carry_the_user_agent() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header_regex(
+            "user-agent",
+            r"^wikirag/\d+\.\d+\.\d+ \(https://",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "query": { "search": [] } })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config = test_config(&server);
+
+    search_wikipedia(&config, "Einstein").await.unwrap();
+}
+
+#[test]
+fn snippets_are_plain_text_cut_at_a_word() {
+    let page = WikiPage {
+        page_id: "1".into(),
+        title: "Albert Einstein".into(),
+        snippet: "<span class=\"searchmatch\">Einstein</span> was a German-born &quot;theoretical&quot;\n physicist who developed relativity".into(),
+        last_edited: None,
+        size: None,
+        wordcount: None,
+    };
+    let cell = TableColumn::Snippet.cell("en", 1, &page);
+    assert_eq!(cell.chars().count(), 50);
+    assert_eq!(
+        cell.trim_end(),
+        "Einstein was a German-born \"theoretical\"..."
+    );
+}
+
+#[tokio::test]
+async fn local_source_searches_titles_before_texts() {
+    let dir = std::env::temp_dir().join(format!("wikirag-local-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("Marie_Curie.txt"), "Marie Curie was a physicist.").unwrap();
+    std::fs::write(
+        dir.join("Radium.txt"),
+        "Radium was discovered by Marie Curie.",
+    )
+    .unwrap();
+    std::fs::write(dir.join("Iron.txt"), "Iron is a metal.").unwrap();
+    let mut config = fixed_config();
+    config.local_source = Some(dir.clone());
+
+    let pages = search_wikipedia(&config, "marie curie").await.unwrap();
+    let titles: Vec<&str> = pages.iter().map(|p| p.title.as_str()).collect();
+    assert_eq!(titles, vec!["Marie Curie", "Radium"]);
+    let (text, _) = download_wikipedia_page(&config, &pages[1].page_id)
+        .await
+        .unwrap();
+    assert!(text.starts_with("Radium"));
+    let e = download_wikipedia_page(&config, "Gold").await.unwrap_err();
+    assert!(e.is::<UnusablePage>());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn answer_prompt_places_question_and_pages() {
+    let mut config = fixed_config();
+    let pages = vec!["Page about {question} marks.".to_string()];
+    config.answer_prompt = Some("Texts:\n{pages}\nAnswer briefly: {question}".into());
+    assert!(separate_pages(&config, &pages).is_empty());
+    assert_eq!(
+        instructed_question(&config, &pages, "Why?"),
+        "Texts:\nPage about {question} marks.\nAnswer briefly: Why?"
+    );
+
+    config.answer_prompt = Some("Answer as a lawyer.".into());
+    assert_eq!(separate_pages(&config, &pages).len(), 1);
+    assert_eq!(
+        instructed_question(&config, &pages, "Why?"),
+        "Answer as a lawyer. Why?"
+    );
+}
+
+#[test]
+fn llm_call_limit_is_an_error_of_its_own() {
+    let mut config = fixed_config();
+    config.max_llm_calls = Some(1);
+    assert!(count_llm_call(&config).is_ok());
+    let e = count_llm_call(&config).unwrap_err();
+    // Wrapped by a backend and classified by the stage, it stays itself:
+    let e = WikiRagError::llm(Box::new(e));
+    assert!(matches!(e, WikiRagError::LlmCallLimit(1)));
+    assert_eq!(e.exit_code(), 8);
+}
+
+#[test]
+fn exit_codes_tell_errors_apart() {
+    let errors = [
+        WikiRagError::Search("s".into()),
+        WikiRagError::Download("d".into()),
+        WikiRagError::Llm("l".into()),
+        WikiRagError::Parse("p".into()),
+        WikiRagError::Config("c".into()),
+        WikiRagError::MissingApiKey("k".into()),
+        WikiRagError::Provider("p".into()),
+        WikiRagError::Offline("o".into()),
+        WikiRagError::ModelUnavailable("m".into()),
+        WikiRagError::Output("w".into()),
+        WikiRagError::NoQuestion,
+        WikiRagError::LlmCallLimit(1),
+        WikiRagError::NoReply {
+            model: "m".into(),
+            reason: "r".into(),
+        },
+    ];
+    let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+    codes.sort();
+    codes.dedup();
+    assert_eq!(codes.len(), errors.len());
+    assert!(!codes.contains(&0) && !codes.contains(&1));
+    let usage = |kind| WikiRagError::Usage(clap::Error::new(kind));
+    assert_eq!(usage(clap::error::ErrorKind::InvalidValue).exit_code(), 1);
+    assert_eq!(usage(clap::error::ErrorKind::DisplayHelp).exit_code(), 0);
+
+    let refused = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+    assert!(matches!(
+        WikiRagError::search(Box::new(refused)),
+        WikiRagError::Offline(_)
+    ));
+}
+
+#[test]
+fn ollama_errors_and_empty_replies_carry_the_reason() {
+    let config = fixed_config();
+    let reply = |response: serde_json::Value| match *llm::ollama_reply(&config, &response)
+        .unwrap_err()
+        .downcast::<WikiRagError>()
+        .unwrap()
+    {
+        WikiRagError::NoReply { model, reason } => (model, reason),
+        e => panic!("unexpected error {:?}", e),
+    };
+
+    let (model, reason) = reply(serde_json::json!({
+        "model": "llama3.2",
+        "error": "llama runner process has terminated: signal: killed"
+    }));
+    assert_eq!(model, "llama3.2");
+    assert_eq!(
+        reason,
+        "llama runner process has terminated: signal: killed"
+    );
+
+    let (_, reason) = reply(serde_json::json!({
+        "model": "llama3.2",
+        "message": { "role": "assistant", "content": "" },
+        "done": true,
+        "done_reason": "load"
+    }));
+    assert!(reason.contains("loaded"), "{}", reason);
+
+    let (_, reason) = reply(serde_json::json!({
+        "model": "llama3.2",
+        "message": { "role": "assistant", "content": "  \n" },
+        "done": true,
+        "done_reason": "length",
+        "prompt_eval_count": 12,
+        "eval_count": 64
+    }));
+    assert!(reason.contains("64 tokens"), "{}", reason);
+
+    let (_, reason) = reply(serde_json::json!({ "model": "llama3.2", "done": false }));
+    assert!(reason.contains("incomplete"), "{}", reason);
+
+    let answer = llm::ollama_reply(
+        &config,
+        &serde_json::json!({
+            "model": "llama3.2",
+            "message": { "role": "assistant", "content": "Paris" },
+            "done": true,
+            "done_reason": "stop"
+        }),
+    );
+    assert_eq!(answer.unwrap(), "Paris");
+}
+
+#[test]
+fn ollama_requests_leave_out_the_oldest_exchanges() {
+    let mut config = fixed_config();
+    config.llm_server = LlmProvider::Ollama;
+    config.model = "llama3.2".into();
+    config.ollama_history_size = 6;
+    let history: Vec<Exchange> = (1..=10)
+        .map(|i| Exchange {
+            question: format!("Question {}?", i),
+            answer: format!("Answer {}.", i),
+        })
+        .collect();
+    let pages = vec!["Paris is the capital of France.".to_string()];
+
+    let request = llm::backend(&config)
+        .answer_request(&pages, &history, "Question 11?")
+        .unwrap();
+    let messages = request["messages"].as_array().unwrap();
+    // The system message with the pages and question 9, answer 9,
+    // question 10, answer 10 and the new question:
+    assert_eq!(messages.len(), 5);
+    let system = messages[0]["content"].as_str().unwrap();
+    assert!(system.contains("Question 9?"), "{}", system);
+    assert!(!system.contains("Question 8?"));
+    assert_eq!(messages[4]["content"], "Question 11?");
+    assert_eq!(retained_history(&config, &history).len(), 2);
+}
+
+#[test]
+fn boolean_settings_can_be_switched_off() {
+    let settings = |pairs: &'static [(&'static str, &'static str)]| {
+        let mut config = fixed_config();
+        apply_settings(&mut config, &|name| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+                .ok_or(std::env::VarError::NotPresent)
+        });
+        config
+    };
+    let off = settings(&[
+        ("RERANK", "0"),
+        ("VERBOSE", "false"),
+        ("DECOMPOSE", "no"),
+        ("QUIET", "off"),
+        ("NO_CACHE", "0"),
+    ]);
+    assert!(!off.rerank && !off.verbose && !off.decompose && !off.quiet);
+    assert!(off.page_cache);
+    let on = settings(&[
+        ("RERANK", "1"),
+        ("VERBOSE", "yes"),
+        ("DECOMPOSE", "TRUE"),
+        ("QUIET", "1"),
+        ("NO_CACHE", "true"),
+    ]);
+    assert!(on.rerank && on.verbose && on.decompose && on.quiet);
+    assert!(!on.page_cache);
+}
+
+#[test]
+fn invalid_answer_schema_is_a_config_error() {
+    let mut config = fixed_config();
+    apply_settings(&mut config, &|name| match name {
+        "ANSWER_SCHEMA" => Ok("/nonexistent/schema.json".into()),
+        _ => Err(std::env::VarError::NotPresent),
+    });
+    assert!(config.answer_schema.is_none());
+    let e = validate_answer_schema(&config).unwrap_err();
+    assert!(matches!(e, WikiRagError::Config(_)));
+    assert!(e.to_string().contains("/nonexistent/schema.json"));
+}
+
+#[test]
+fn client_errors_are_permanent_and_server_errors_transient() {
+    let status = |status| {
+        retry::classify(&retry::HttpStatusError {
+            status,
+            message: "failed".into(),
+        })
+    };
+    assert!(matches!(status(400), retry::Failure::Permanent(_)));
+    assert!(matches!(status(404), retry::Failure::Permanent(_)));
+    assert!(matches!(status(429), retry::Failure::Transient(_)));
+    assert!(matches!(status(500), retry::Failure::Transient(_)));
+    assert!(matches!(status(503), retry::Failure::Transient(_)));
+
+    let api = |code: &str| {
+        retry::classify(&retry::WikiApiError {
+            code: code.into(),
+            info: String::new(),
+        })
+    };
+    assert!(matches!(api("maxlag"), retry::Failure::Transient(_)));
+    assert!(matches!(api("ratelimited"), retry::Failure::Transient(_)));
+    assert!(matches!(api("badvalue"), retry::Failure::Permanent(_)));
+}
+
+#[tokio::test]
+async fn reqwest_failures_are_classified_by_status_and_timeout() {
+    let server = MockServer::start().await;
+    Mock::given(path("/missing"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    Mock::given(path("/slow"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+        .mount(&server)
+        .await;
+    let client = http_client(Duration::from_millis(100), "wikirag-test");
+
+    let e = client
+        .get(format!("{}/missing", server.uri()))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap_err();
+    assert!(matches!(retry::classify(&e), retry::Failure::Permanent(_)));
+
+    let e = client
+        .get(format!("{}/slow", server.uri()))
+        .send()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        retry::classify(&e),
+        retry::Failure::Transient("timeout".into())
+    );
+}
+
+#[test]
+fn keywords_are_cleaned_of_model_chatter() {
+    for (raw, keyword) in [
+        ("Albert Einstein", "Albert Einstein"),
+        ("Keyword: Albert Einstein", "Albert Einstein"),
+        ("The keyword is: \"Albert Einstein\".", "Albert Einstein"),
+        (
+            "Sure, here is the keyword: **Photosynthesis**",
+            "Photosynthesis",
+        ),
+        ("Search term - `Black hole`", "Black hole"),
+        (
+            "The best Wikipedia keyword would be 'Mount Everest'!",
+            "Mount Everest",
+        ),
+        ("“Eiffel Tower”", "Eiffel Tower"),
+        ("Washington, D.C.", "Washington, D.C."),
+        ("Photosynthesis.", "Photosynthesis"),
+        // A real keyword which merely starts like a lead-in stays:
+        ("Term limits", "Term limits"),
+        ("Category theory", "Category theory"),
+    ] {
+        assert_eq!(sanitize_keyword(raw), keyword, "for {:?}", raw);
+    }
+}
+
+#[test]
+fn keyword_responses_with_prose_give_clean_keywords() {
+    let mut config = fixed_config();
+    config.keywords = 1;
+    config.keyword_alternatives = 0;
+    let chain = keyword_chain(
+        &config,
+        "Sure! Here is the keyword for your question:\n\nThe keyword is: \"Theory of relativity\".",
+    );
+    assert_eq!(chain, vec![vec!["Theory of relativity".to_string()]]);
+    let chain = keyword_chain(
+        &config,
+        "Let me think.\nEinstein might be too broad.\nRelativity",
+    );
+    assert_eq!(chain, vec![vec!["Relativity".to_string()]]);
+
+    config.keyword_alternatives = 2;
+    let chain = keyword_chain(
+        &config,
+        "Here are the keywords:\n1. Relativity\n2. Albert Einstein",
+    );
+    assert_eq!(
+        chain,
+        vec![
+            vec!["Relativity".to_string()],
+            vec!["Albert Einstein".to_string()]
+        ]
+    );
+}
+
+/// A chat completion response as the API sends it, with the given choices.
+fn chat_completion(choices: serde_json::Value) -> CreateChatCompletionResponse {
+    serde_json::from_value(serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 1700000000,
+        "model": "gpt-4o-mini",
+        "choices": choices
+    }))
+    .unwrap()
+}
+
+#[test]
+fn answer_needs_a_choice_with_content() {
+    let response = chat_completion(serde_json::json!([
+        {
+            "index": 0,
+            "message": { "role": "assistant", "content": "Paris" },
+            "finish_reason": "stop"
+        },
+        {
+            "index": 1,
+            "message": { "role": "assistant", "content": null },
+            "finish_reason": "content_filter"
+        }
+    ]));
+    assert_eq!(extract_answer(&response, 0).unwrap(), "Paris");
+
+    let e = extract_answer(&response, 1).unwrap_err();
+    assert!(e.to_string().contains("has no content"), "{}", e);
+
+    let e = extract_answer(&response, 2).unwrap_err();
+    assert!(e.to_string().contains("no choice 2 (2 choices"), "{}", e);
+
+    let empty = chat_completion(serde_json::json!([]));
+    let e = extract_answer(&empty, 0).unwrap_err();
+    assert!(e.to_string().contains("0 choices received"), "{}", e);
+}
+
+#[test]
+fn long_search_terms_are_cut_at_a_word() {
+    let config = fixed_config();
+    let term = "relativity ".repeat(40);
+    let cut = check_search_term(&config, &term).unwrap();
+    assert!(cut.chars().count() <= MAX_SEARCH_LEN);
+    assert!(cut.ends_with("relativity"));
+    assert!(!cut.ends_with(' '));
+
+    let short = check_search_term(&config, "  Albert Einstein \n").unwrap();
+    assert_eq!(short, "Albert Einstein");
+
+    let word = "ä".repeat(MAX_SEARCH_LEN);
+    assert_eq!(check_search_term(&config, &word).unwrap(), word);
+
+    assert!(check_search_term(&config, "   ").is_err());
+    assert!(check_search_term(&config, "Einstein\u{7}").is_err());
+}
+
+#[tokio::test]
+async fn special_characters_reach_the_search_unchanged() {
+    let server = MockServer::start().await;
+    let keyword = "C++ & \"Rust\" #1 50% Zürich/Genève?";
+    Mock::given(method("GET"))
+        .and(query_param("srsearch", keyword))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "query": { "search": [ { "pageid": 7, "title": "Programming languages" } ] }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config = test_config(&server);
+
+    let pages = search_wikipedia(&config, keyword).await.unwrap();
+    assert_eq!(pages[0].page_id, "7");
+}
+
+#[test]
+fn titles_with_forbidden_characters_are_rejected() {
+    assert!(check_title("São Paulo (state)").is_ok());
+    assert!(check_title("AT&T").is_ok());
+    for title in ["C# [language]", "a|b", "x{y}", "<tag>", "Tab\there"] {
+        assert!(check_title(title).is_err(), "{}", title);
+    }
+    assert!(check_title(&"é".repeat(128)).is_err());
+}
+
+#[test]
+fn prompts_are_shown_on_a_terminal_by_default() {
+    assert!(PromptMode::Auto.shows_prompt(true));
+    assert!(!PromptMode::Auto.shows_prompt(false));
+    assert!(PromptMode::Always.shows_prompt(false));
+    assert!(!PromptMode::Never.shows_prompt(true));
+}
+
+#[tokio::test]
+async fn category_members_come_as_a_list() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("list", "categorymembers"),
+        serde_json::json!({
+            "batchcomplete": true,
+            "query": { "categorymembers": [
+                { "pageid": 736, "ns": 0, "title": "Albert Einstein" },
+                { "pageid": 24452, "ns": 0, "title": "Max Planck" }
+            ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let pages = search_category(&config, "German physicists").await.unwrap();
+    let titles: Vec<&str> = pages.iter().map(|p| p.title.as_str()).collect();
+    assert_eq!(titles, ["Albert Einstein", "Max Planck"]);
+    assert_eq!(pages[1].page_id, "24452");
+}
+
+#[tokio::test]
+async fn linked_pages_leave_out_missing_ones() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("generator", "links"),
+        serde_json::json!({
+            "batchcomplete": true,
+            "query": { "pages": [
+                { "pageid": 19048, "ns": 0, "title": "Mass–energy equivalence" },
+                { "ns": 0, "title": "Unwritten article", "missing": true }
+            ] }
+        }),
+    )
+    .await;
+    // A page without links has no query at all:
+    Mock::given(method("GET"))
+        .and(query_param("pageids", "5"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "batchcomplete": true })),
+        )
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    let config = test_config(&server);
+
+    let pages = fetch_linked_pages(&config, "736").await.unwrap();
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages[0].title, "Mass–energy equivalence");
+    assert!(fetch_linked_pages(&config, "5").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn revisions_and_assessments_come_per_page() {
+    let server = MockServer::start().await;
+    respond(
+        &server,
+        ("prop", "revisions"),
+        serde_json::json!({
+            "query": { "pages": [
+                { "pageid": 736, "ns": 0, "title": "Albert Einstein",
+                  "revisions": [ { "timestamp": "2024-05-01T12:00:00Z" } ] },
+                { "pageid": 9, "missing": true }
+            ] }
+        }),
+    )
+    .await;
+    respond(
+        &server,
+        ("prop", "pageassessments"),
+        serde_json::json!({
+            "query": { "pages": [
+                { "pageid": 736, "ns": 0, "title": "Albert Einstein",
+                  "pageassessments": {
+                      "Physics": { "class": "B", "importance": "Top" },
+                      "Biography": { "class": "FA", "importance": "High" }
+                  } },
+                { "pageid": 9, "ns": 0, "title": "Unassessed" }
+            ] }
+        }),
+    )
+    .await;
+    let config = test_config(&server);
+
+    let edited = fetch_last_edited(&config, &["736", "9"]).await.unwrap();
+    assert_eq!(
+        edited,
+        [("736".to_string(), "2024-05-01T12:00:00Z".to_string())]
+    );
+    let classes = fetch_quality_classes(&config, &["736", "9"]).await.unwrap();
+    assert_eq!(classes, [("736".to_string(), "FA".to_string())]);
+}
+
+#[test]
+fn map_reduce_pages_get_the_whole_window_each() {
+    let mut config = fixed_config();
+    // 1000 tokens, or 4000 characters, for the page texts:
+    config.context_tokens = Some(2500);
+    config.answer_max_tokens = 500;
+    let paragraph = "The city lies on the river and has a long history.\n\n";
+    let text = paragraph.repeat(60);
+    let page = |id: &str| WikiPage {
+        page_id: id.into(),
+        title: format!("Page {}", id),
+        snippet: String::new(),
+        last_edited: None,
+        size: None,
+        wordcount: None,
+    };
+    let retrieval = Retrieval {
+        keyword_chain: vec!["city".into()],
+        keywords: "city".into(),
+        pages: vec![page("1"), page("2")],
+        page_strings: vec![text.clone(), text.clone()],
+    };
+
+    let shared = context_pages(&config, &retrieval);
+    assert!(shared.iter().map(|t| t.len()).sum::<usize>() <= 4000);
+    let single = single_context_pages(&config, &retrieval);
+    for t in &single {
+        assert!(t.len() > 2000 && t.len() <= 4000, "{}", t.len());
+    }
+}
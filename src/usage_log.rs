@@ -0,0 +1,37 @@
+//! A log of what LLM calls cost over time: with `USAGE_LOG` set, every
+//! call appends a CSV row `timestamp,model,tokens_in,tokens_out,cost` to
+//! that file, so that the spend of many runs can be added up.
+
+use crate::{progress, Config};
+use std::io::Write;
+
+const HEADER: &str = "timestamp,model,tokens_in,tokens_out,cost";
+
+/// Appends a row for a call, with a header if the file is new. Failures
+/// are reported, but do not stop the run.
+pub fn append(config: &Config, prompt_tokens: u64, completion_tokens: u64, cost: f64) {
+    let Some(path) = &config.usage_log else {
+        return;
+    };
+    let row = format!(
+        "{},{},{},{},{:.6}\n",
+        chrono::Local::now().to_rfc3339(),
+        config.model,
+        prompt_tokens,
+        completion_tokens,
+        cost
+    );
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| {
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", HEADER)?;
+            }
+            file.write_all(row.as_bytes())
+        });
+    if let Err(e) = written {
+        progress!(config, "Could not write to the usage log {}: {}", path, e);
+    }
+}